@@ -164,7 +164,7 @@ impl AciaServer {
                 let mut buf: [u8; 32] = [0; 32];
                 loop {
                     let n = match reader.read(&mut buf).await {
-                        Ok(n) if n == 0 => {
+                        Ok(0) => {
                             error!("Read 0 bytes... bye.");
                             write_state.lock().unwrap().connected = false;
                             return;
@@ -0,0 +1,118 @@
+//! Uniflex filesystem tool for Tektronix 4404 disk images
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+#[path = "../uniflex.rs"]
+mod uniflex;
+
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use uniflex::{Filesystem, BLOCK_SIZE};
+
+/// Clap options parsed from the command line
+#[derive(Parser, Debug)]
+#[clap(about = "Tektronix 4404 Uniflex Filesystem Tool")]
+struct Opts {
+    /// The disk image to operate on
+    image: String,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List a directory
+    Ls {
+        /// The directory to list
+        #[clap(default_value = "/")]
+        path: String,
+    },
+    /// Copy a file out of the image to the host
+    Extract {
+        /// The file in the image
+        path: String,
+        /// Where to write it on the host
+        dest: String,
+    },
+    /// Copy a host file into the image, replacing any existing file
+    Inject {
+        /// The file on the host
+        source: String,
+        /// Where to write it in the image
+        path: String,
+    },
+    /// Write an empty filesystem covering the whole image
+    Mkfs {
+        /// The filesystem and pack name
+        #[clap(short, long, default_value = "tek4404")]
+        name: String,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opts: Opts = Opts::parse();
+    let image = opts.image.as_str();
+
+    match opts.command {
+        Command::Ls { path } => {
+            let mut fs = Filesystem::open(image, false)?;
+            let dir = fs.lookup(&path)?;
+            for entry in fs.list(dir)? {
+                let fdn = fs.read_fdn(entry.fdn)?;
+                println!(
+                    "{} {:3} {:5} {:9} {}",
+                    fdn.mode_string(),
+                    fdn.links,
+                    fdn.owner,
+                    fdn.size,
+                    entry.name
+                );
+            }
+        }
+        Command::Extract { path, dest } => {
+            let mut fs = Filesystem::open(image, false)?;
+            let fdn = fs.stat(&path)?;
+            if fdn.is_dir() {
+                return Err(uniflex::FsError::IsADirectory(path).into());
+            }
+            std::fs::write(dest, fs.read_file(&fdn)?)?;
+        }
+        Command::Inject { source, path } => {
+            let data = std::fs::read(source)?;
+            let mut fs = Filesystem::open(image, true)?;
+            fs.write_file(&path, &data)?;
+        }
+        Command::Mkfs { name } => {
+            let blocks = std::fs::metadata(image)?.len() / BLOCK_SIZE as u64;
+            let fs = Filesystem::format(image, blocks.min(u32::MAX as u64) as u32, &name)?;
+            println!(
+                "{}: {} blocks, {} free",
+                image,
+                fs.volume_size(),
+                fs.free_blocks()
+            );
+        }
+    }
+
+    Ok(())
+}
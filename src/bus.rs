@@ -28,6 +28,7 @@ use crate::cpu;
 use crate::duart::*;
 use crate::err::*;
 use crate::fpu::*;
+use crate::irq::Interrupt;
 use crate::mem::*;
 use crate::mmu::*;
 use crate::mouse::*;
@@ -36,9 +37,12 @@ use crate::service::*;
 use crate::sound::*;
 use crate::timer::*;
 use crate::video::*;
+use crate::vram::*;
 
 use log::{error, trace};
+use num_traits::FromPrimitive;
 use once_cell::sync::Lazy;
+use std::fmt;
 use std::os::raw::c_uint;
 use std::sync::{Arc, Mutex};
 
@@ -117,15 +121,59 @@ pub static QUEUE: Lazy<Mutex<ServiceQueue>> = Lazy::new(|| Mutex::new(ServiceQue
 
 macro_rules! schedule {
     ($key:expr, $delay:expr) => {
-        QUEUE.lock().unwrap().schedule($key, $delay);
+        schedule!($key, 0, $delay)
+    };
+    ($key:expr, $tag:expr, $delay:expr) => {
+        QUEUE.lock().unwrap().schedule($key, $tag, $delay);
+    };
+}
+
+macro_rules! cancel {
+    ($key:expr) => {
+        cancel!($key, 0)
+    };
+    ($key:expr, $tag:expr) => {
+        QUEUE.lock().unwrap().cancel($key, $tag);
     };
 }
 
+/// The function code of a bus access, as driven on FC0-FC2 by the
+/// 68010. User space addresses are translated by the MMU; supervisor
+/// space addresses are physical.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FunctionCode(pub u8);
+
+impl FunctionCode {
+    pub const USER_DATA: FunctionCode = FunctionCode(1);
+    pub const USER_PROGRAM: FunctionCode = FunctionCode(2);
+    pub const SUPERVISOR_DATA: FunctionCode = FunctionCode(5);
+    pub const SUPERVISOR_PROGRAM: FunctionCode = FunctionCode(6);
+    pub const CPU_SPACE: FunctionCode = FunctionCode(7);
+
+    pub fn is_user(self) -> bool {
+        self.0 & 4 == 0
+    }
+}
+
+impl fmt::Display for FunctionCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FunctionCode::USER_DATA => write!(f, "user data"),
+            FunctionCode::USER_PROGRAM => write!(f, "user program"),
+            FunctionCode::SUPERVISOR_DATA => write!(f, "supervisor data"),
+            FunctionCode::SUPERVISOR_PROGRAM => write!(f, "supervisor program"),
+            FunctionCode::CPU_SPACE => write!(f, "CPU space"),
+            FunctionCode(fc) => write!(f, "reserved ({fc})"),
+        }
+    }
+}
+
 pub type BusDevice = Arc<Mutex<dyn IoDevice + Send + Sync>>;
 pub type MemoryDevice = Arc<Mutex<Memory>>;
 pub type SoundDevice = Arc<Mutex<Sound>>;
 pub type AciaDevice = Arc<Mutex<Acia>>;
 pub type VideoDevice = Arc<Mutex<Video>>;
+pub type VideoRamDevice = Arc<Mutex<VideoRam>>;
 pub type DuartDevice = Arc<Mutex<Duart>>;
 pub type MmuDevice = Arc<Mutex<Mmu>>;
 pub type FpuDevice = Arc<Mutex<Fpu>>;
@@ -142,7 +190,7 @@ pub struct Bus {
     pub sound: Option<SoundDevice>,
     pub acia: Option<AciaDevice>,
     pub video: Option<VideoDevice>,
-    pub video_ram: Option<MemoryDevice>,
+    pub video_ram: Option<VideoRamDevice>,
     pub duart: Option<DuartDevice>,
     pub diag: Option<MemoryDevice>,
     pub fpu: Option<FpuDevice>,
@@ -179,7 +227,89 @@ impl Bus {
         }
     }
 
-    fn map_device(&mut self, addr: usize) -> Result<BusDevice, BusError> {
+    /// Translate a user space address to a physical address.
+    fn translate(&mut self, addr: usize, fc: FunctionCode, write: bool) -> Result<usize, BusError> {
+        match &self.mmu {
+            Some(mmu) => mmu.lock().unwrap().translate(addr, fc, write),
+            None => Ok(addr),
+        }
+    }
+
+    /// Hand a due service request to the device that made it.
+    pub fn service(&mut self, srq: ServiceRequest) {
+        let device: Option<BusDevice> = match srq.key {
+            ServiceKey::Scsi => self.scsi.clone().map(|d| d as BusDevice),
+            ServiceKey::Video => self.video.clone().map(|d| d as BusDevice),
+        };
+        match device {
+            Some(device) => device.lock().unwrap().service(self, srq.tag),
+            None => error!("No device to service {:?}", srq),
+        }
+    }
+
+    /// Run an interrupt acknowledge cycle for `level`, returning the
+    /// vector supplied by the device requesting it, if any.
+    pub fn acknowledge(&mut self, level: u8) -> Option<u8> {
+        let device: BusDevice = match FromPrimitive::from_u8(level)? {
+            Interrupt::Uart => self.duart.clone()?,
+            Interrupt::Scsi | Interrupt::Dma => self.scsi.clone()?,
+            _ => return None,
+        };
+        let vector = device.lock().unwrap().vector();
+        vector
+    }
+
+    /// Find the device answering an access, and the physical address
+    /// it sees.
+    fn map_device(
+        &mut self,
+        fc: FunctionCode,
+        addr: usize,
+        write: bool,
+    ) -> Result<(BusDevice, usize), BusError> {
+        if !fc.is_user() {
+            return Ok((self.map_physical(addr)?, addr));
+        }
+
+        let addr = self.translate(addr, fc, write)?;
+        // User programs may only reach memory. Anything else is
+        // reserved to the supervisor.
+        match addr {
+            RAM_START..=RAM_END | VRAM_START..=VRAM_END => Ok((self.map_physical(addr)?, addr)),
+            _ => {
+                error!("{} access to supervisor address {:08x}", fc, addr);
+                Err(BusError::Access)
+            }
+        }
+    }
+
+    /// Find the memory behind an address for the debugger. Unlike
+    /// map_device, this leaves the MMU's REF and MOD bits and fault
+    /// registers alone, and refuses device registers, which may have
+    /// side effects when read.
+    fn map_memory(
+        &mut self,
+        fc: FunctionCode,
+        addr: usize,
+    ) -> Result<(BusDevice, usize), BusError> {
+        let addr = match (&self.mmu, fc.is_user()) {
+            (Some(mmu), true) => mmu
+                .lock()
+                .unwrap()
+                .lookup(addr, false)
+                .ok_or(BusError::Access)?,
+            _ => addr,
+        };
+
+        match addr {
+            RAM_START..=RAM_END | VRAM_START..=VRAM_END | ROM_START..=ROM_END => {
+                Ok((self.map_physical(addr)?, addr))
+            }
+            _ => Err(BusError::Access),
+        }
+    }
+
+    fn map_physical(&mut self, addr: usize) -> Result<BusDevice, BusError> {
         match addr {
             RAM_START..=RAM_END => {
                 if self.map_rom {
@@ -261,46 +391,69 @@ impl Bus {
         }
     }
 
-    fn read_8(&mut self, address: usize) -> Result<u8, BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .read_8(self, address)
+    pub fn read_8(&mut self, fc: FunctionCode, address: usize) -> Result<u8, BusError> {
+        let (device, address) = self.map_device(fc, address, false)?;
+        let result = device.lock().unwrap().read_8(self, address);
+        result
+    }
+
+    pub fn read_16(&mut self, fc: FunctionCode, address: usize) -> Result<u16, BusError> {
+        let (device, address) = self.map_device(fc, address, false)?;
+        let result = device.lock().unwrap().read_16(self, address);
+        result
+    }
+
+    pub fn read_32(&mut self, fc: FunctionCode, address: usize) -> Result<u32, BusError> {
+        let (device, address) = self.map_device(fc, address, false)?;
+        let result = device.lock().unwrap().read_32(self, address);
+        result
     }
 
-    fn read_16(&mut self, address: usize) -> Result<u16, BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .read_16(self, address)
+    /// Read memory without side effects, for the disassembler.
+    pub fn peek_8(&mut self, fc: FunctionCode, address: usize) -> Result<u8, BusError> {
+        let (device, address) = self.map_memory(fc, address)?;
+        let result = device.lock().unwrap().read_8(self, address);
+        result
     }
 
-    fn read_32(&mut self, address: usize) -> Result<u32, BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .read_32(self, address)
+    pub fn peek_16(&mut self, fc: FunctionCode, address: usize) -> Result<u16, BusError> {
+        let (device, address) = self.map_memory(fc, address)?;
+        let result = device.lock().unwrap().read_16(self, address);
+        result
     }
 
-    fn write_8(&mut self, address: usize, value: u8) -> Result<(), BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .write_8(self, address, value)
+    pub fn peek_32(&mut self, fc: FunctionCode, address: usize) -> Result<u32, BusError> {
+        let (device, address) = self.map_memory(fc, address)?;
+        let result = device.lock().unwrap().read_32(self, address);
+        result
     }
 
-    fn write_16(&mut self, address: usize, value: u16) -> Result<(), BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .write_16(self, address, value)
+    pub fn write_8(&mut self, fc: FunctionCode, address: usize, value: u8) -> Result<(), BusError> {
+        let (device, address) = self.map_device(fc, address, true)?;
+        let result = device.lock().unwrap().write_8(self, address, value);
+        result
     }
 
-    fn write_32(&mut self, address: usize, value: u32) -> Result<(), BusError> {
-        self.map_device(address)?
-            .lock()
-            .unwrap()
-            .write_32(self, address, value)
+    pub fn write_16(
+        &mut self,
+        fc: FunctionCode,
+        address: usize,
+        value: u16,
+    ) -> Result<(), BusError> {
+        let (device, address) = self.map_device(fc, address, true)?;
+        let result = device.lock().unwrap().write_16(self, address, value);
+        result
+    }
+
+    pub fn write_32(
+        &mut self,
+        fc: FunctionCode,
+        address: usize,
+        value: u32,
+    ) -> Result<(), BusError> {
+        let (device, address) = self.map_device(fc, address, true)?;
+        let result = device.lock().unwrap().write_32(self, address, value);
+        result
     }
 }
 
@@ -331,12 +484,23 @@ pub trait IoDevice {
         Ok(())
     }
 
-    fn service(&mut self) {}
+    /// Service a request the device scheduled with `tag`.
+    fn service(&mut self, _bus: &mut Bus, _tag: u8) {}
+
+    /// Answer an interrupt acknowledge cycle with a vector number, or
+    /// None to have the CPU autovector.
+    fn vector(&mut self) -> Option<u8> {
+        None
+    }
 }
 
 #[no_mangle]
 pub fn m68k_read_disassembler_8(address: c_uint) -> c_uint {
-    match BUS.lock().unwrap().read_8(address as usize) {
+    match BUS
+        .lock()
+        .unwrap()
+        .peek_8(cpu::program_space(), address as usize)
+    {
         Ok(byte) => byte as c_uint,
         Err(_) => 0,
     }
@@ -344,7 +508,11 @@ pub fn m68k_read_disassembler_8(address: c_uint) -> c_uint {
 
 #[no_mangle]
 pub fn m68k_read_disassembler_16(address: c_uint) -> c_uint {
-    match BUS.lock().unwrap().read_16(address as usize) {
+    match BUS
+        .lock()
+        .unwrap()
+        .peek_16(cpu::program_space(), address as usize)
+    {
         Ok(byte) => byte as c_uint,
         Err(_) => 0,
     }
@@ -352,7 +520,11 @@ pub fn m68k_read_disassembler_16(address: c_uint) -> c_uint {
 
 #[no_mangle]
 pub fn m68k_read_disassembler_32(address: c_uint) -> c_uint {
-    match BUS.lock().unwrap().read_32(address as usize) {
+    match BUS
+        .lock()
+        .unwrap()
+        .peek_32(cpu::program_space(), address as usize)
+    {
         Ok(byte) => byte as c_uint,
         Err(_) => 0,
     }
@@ -360,7 +532,10 @@ pub fn m68k_read_disassembler_32(address: c_uint) -> c_uint {
 
 #[no_mangle]
 pub fn m68k_read_memory_8(address: c_uint) -> c_uint {
-    let result = BUS.lock().unwrap().read_8(address as usize);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .read_8(cpu::function_code(), address as usize);
 
     match result {
         Ok(byte) => {
@@ -376,7 +551,10 @@ pub fn m68k_read_memory_8(address: c_uint) -> c_uint {
 
 #[no_mangle]
 pub fn m68k_read_memory_16(address: c_uint) -> c_uint {
-    let result = BUS.lock().unwrap().read_16(address as usize);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .read_16(cpu::function_code(), address as usize);
 
     match result {
         Ok(word) => {
@@ -392,7 +570,10 @@ pub fn m68k_read_memory_16(address: c_uint) -> c_uint {
 
 #[no_mangle]
 pub fn m68k_read_memory_32(address: c_uint) -> c_uint {
-    let result = BUS.lock().unwrap().read_32(address as usize);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .read_32(cpu::function_code(), address as usize);
 
     match result {
         Ok(long) => {
@@ -409,7 +590,10 @@ pub fn m68k_read_memory_32(address: c_uint) -> c_uint {
 #[no_mangle]
 pub fn m68k_write_memory_8(addr: c_uint, val: c_uint) {
     trace!("[WRITE] [BYTE] {:08x} = {:02x}", addr, val);
-    let result = BUS.lock().unwrap().write_8(addr as usize, val as u8);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .write_8(cpu::function_code(), addr as usize, val as u8);
     match result {
         Ok(()) => {}
         Err(BusError::ReadOnly) => {
@@ -422,7 +606,10 @@ pub fn m68k_write_memory_8(addr: c_uint, val: c_uint) {
 #[no_mangle]
 pub fn m68k_write_memory_16(addr: c_uint, val: c_uint) {
     trace!("[WRITE] [WORD] {:08x} = {:04x}", addr, val);
-    let result = BUS.lock().unwrap().write_16(addr as usize, val as u16);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .write_16(cpu::function_code(), addr as usize, val as u16);
     match result {
         Ok(()) => {}
         Err(BusError::ReadOnly) => {
@@ -435,7 +622,10 @@ pub fn m68k_write_memory_16(addr: c_uint, val: c_uint) {
 #[no_mangle]
 pub fn m68k_write_memory_32(addr: c_uint, val: c_uint) {
     trace!("[WRITE] [LONG] {:08x} = {:08x}", addr, val);
-    let result = BUS.lock().unwrap().write_32(addr as usize, val);
+    let result = BUS
+        .lock()
+        .unwrap()
+        .write_32(cpu::function_code(), addr as usize, val);
     match result {
         Ok(()) => {}
         Err(BusError::ReadOnly) => {
@@ -450,6 +640,9 @@ mod tests {
     use super::*;
     use std::panic;
 
+    const SUPER: FunctionCode = FunctionCode::SUPERVISOR_DATA;
+    const USER: FunctionCode = FunctionCode::USER_DATA;
+
     fn with_bus<T>(test: T)
     where
         T: FnOnce(&mut Bus) + panic::UnwindSafe,
@@ -477,22 +670,22 @@ mod tests {
         #[test]
         fn test_read_write_8() {
             with_bus(|bus| {
-                bus.write_8(0x100, 0x01).unwrap();
-                assert_eq!(0x01, bus.read_8(0x100).unwrap());
+                bus.write_8(SUPER, 0x100, 0x01).unwrap();
+                assert_eq!(0x01, bus.read_8(SUPER, 0x100).unwrap());
             })
         }
 
         #[test]
         fn test_read_write_8_bad_address() {
             with_bus(|bus| {
-                assert_eq!(Err(BusError::Access), bus.write_8(0x2000000, 0x01));
+                assert_eq!(Err(BusError::Access), bus.write_8(SUPER, 0x2000000, 0x01));
             });
         }
 
         #[test]
         fn test_read_write_8_read_only() {
             with_bus(|bus| {
-                let result = bus.write_8(0x740000, 0x01);
+                let result = bus.write_8(SUPER, 0x740000, 0x01);
                 assert_eq!(Err(BusError::ReadOnly), result);
             })
         }
@@ -500,23 +693,23 @@ mod tests {
         #[test]
         fn test_read_write_16() {
             with_bus(|bus| {
-                bus.write_16(0x100, 0x0102).unwrap();
-                assert_eq!(0x0102, bus.read_16(0x100).unwrap());
+                bus.write_16(SUPER, 0x100, 0x0102).unwrap();
+                assert_eq!(0x0102, bus.read_16(SUPER, 0x100).unwrap());
             })
         }
 
         #[test]
         fn test_read_write_16_alignment() {
             with_bus(|bus| {
-                assert_eq!(Err(BusError::Alignment), bus.write_16(0x101, 0x0102));
-                assert_eq!(Err(BusError::Alignment), bus.read_16(0x101));
+                assert_eq!(Err(BusError::Alignment), bus.write_16(SUPER, 0x101, 0x0102));
+                assert_eq!(Err(BusError::Alignment), bus.read_16(SUPER, 0x101));
             })
         }
 
         #[test]
         fn test_read_write_16_bad_address() {
             with_bus(|bus| {
-                let result = bus.write_16(0x2000000, 0x0102);
+                let result = bus.write_16(SUPER, 0x2000000, 0x0102);
                 assert_eq!(Err(BusError::Access), result);
             })
         }
@@ -524,7 +717,7 @@ mod tests {
         #[test]
         fn test_read_write_16_read_only() {
             with_bus(|bus| {
-                let result = bus.write_16(0x740000, 0x0102);
+                let result = bus.write_16(SUPER, 0x740000, 0x0102);
                 assert_eq!(Err(BusError::ReadOnly), result);
             })
         }
@@ -532,23 +725,26 @@ mod tests {
         #[test]
         fn test_read_write_32() {
             with_bus(|bus| {
-                bus.write_32(0x100, 0x01020304).unwrap();
-                assert_eq!(0x01020304, bus.read_32(0x100).unwrap());
+                bus.write_32(SUPER, 0x100, 0x01020304).unwrap();
+                assert_eq!(0x01020304, bus.read_32(SUPER, 0x100).unwrap());
             })
         }
 
         #[test]
         fn test_read_write_32_alignment() {
             with_bus(|bus| {
-                assert_eq!(Err(BusError::Alignment), bus.write_32(0x101, 0x01020304));
-                assert_eq!(Err(BusError::Alignment), bus.read_32(0x101));
+                assert_eq!(
+                    Err(BusError::Alignment),
+                    bus.write_32(SUPER, 0x101, 0x01020304)
+                );
+                assert_eq!(Err(BusError::Alignment), bus.read_32(SUPER, 0x101));
             })
         }
 
         #[test]
         fn test_read_write_32_bad_address() {
             with_bus(|bus| {
-                let result = bus.write_32(0x2000000, 0x01020304);
+                let result = bus.write_32(SUPER, 0x2000000, 0x01020304);
                 assert_eq!(Err(BusError::Access), result);
             })
         }
@@ -556,9 +752,73 @@ mod tests {
         #[test]
         fn test_read_write_32_read_only() {
             with_bus(|bus| {
-                let result = bus.write_16(0x740000, 0x0102);
+                let result = bus.write_16(SUPER, 0x740000, 0x0102);
                 assert_eq!(Err(BusError::ReadOnly), result);
             })
         }
     }
+
+    #[test]
+    fn test_interrupt_acknowledge() {
+        with_bus(|bus| {
+            bus.duart = Some(Arc::new(Mutex::new(Duart::new())));
+            bus.write_8(SUPER, DUART_START + 0x18, 0x40).unwrap();
+
+            assert_eq!(Some(0x40), bus.acknowledge(5));
+            // Nothing on the bus supplies a vector for the SCSI level.
+            assert_eq!(None, bus.acknowledge(3));
+        })
+    }
+
+    mod mmu {
+        use super::*;
+
+        #[test]
+        fn test_user_space_translation() {
+            with_bus(|bus| {
+                // Map user page 3 to physical page 0x10, read-only.
+                bus.write_16(SUPER, PT_START + 0x3000, PTE_VALID | 0x10)
+                    .unwrap();
+                bus.write_8(SUPER, MMU_START, 1).unwrap();
+
+                bus.write_8(SUPER, 0x10123, 0x5a).unwrap();
+                assert_eq!(0x5a, bus.read_8(USER, 0x3123).unwrap());
+                assert_eq!(Err(BusError::Access), bus.write_8(USER, 0x3123, 0));
+                assert_eq!(Err(BusError::Access), bus.read_8(USER, 0x4000));
+
+                // Supervisor accesses are not translated.
+                assert_eq!(0, bus.read_8(SUPER, 0x3123).unwrap());
+            })
+        }
+
+        #[test]
+        fn test_peek() {
+            with_bus(|bus| {
+                bus.write_16(SUPER, PT_START + 0x3000, PTE_VALID | 0x10)
+                    .unwrap();
+                bus.write_8(SUPER, 0x10123, 0x5a).unwrap();
+                bus.write_8(SUPER, MMU_START, 1).unwrap();
+
+                assert_eq!(Ok(0x5a), bus.peek_8(USER, 0x3123));
+                assert_eq!(Err(BusError::Access), bus.peek_8(USER, 0x4000));
+                assert_eq!(Err(BusError::Access), bus.peek_8(SUPER, MMU_START));
+
+                // No fault was latched and the page is not referenced.
+                assert_eq!(Ok(0), bus.read_16(SUPER, MMU_START + 2));
+                bus.write_8(SUPER, MMU_START, 0).unwrap();
+                assert_eq!(Ok(PTE_VALID | 0x10), bus.read_16(SUPER, PT_START + 0x3000));
+            })
+        }
+
+        #[test]
+        fn test_user_io_access() {
+            with_bus(|bus| {
+                assert_eq!(Ok(()), bus.write_8(USER, 0x100, 1));
+                assert_eq!(Err(BusError::Access), bus.write_8(USER, MMU_START, 1));
+                assert_eq!(Err(BusError::Access), bus.read_16(USER, PT_START));
+                assert_eq!(Err(BusError::Access), bus.read_16(USER, ROM_START));
+                assert_eq!(Ok(0), bus.read_8(SUPER, MMU_START));
+            })
+        }
+    }
 }
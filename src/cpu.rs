@@ -21,13 +21,27 @@
 // WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
+use crate::bus::{FunctionCode, BUS};
+
 use log::{debug, log_enabled, trace, Level};
+use std::cell::Cell;
 use std::ffi::CStr;
-use std::os::raw::{c_char, c_int, c_uint};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The 4404's CPU clock
+pub const CLOCK_HZ: u64 = 10_000_000;
 
 const M68K_CPU_TYPE_68010: c_uint = 2;
+const M68K_REG_SR: c_uint = 17;
+
+const SR_SUPERVISOR: c_uint = 0x2000;
+
+const M68K_INT_ACK_AUTOVECTOR: c_int = -1;
 
 type InstructionHook = extern "C" fn(pc: c_uint);
+type FunctionCodeCallback = extern "C" fn(fc: c_uint);
+type InterruptAckCallback = extern "C" fn(level: c_int) -> c_int;
 
 extern "C" {
     pub fn m68k_set_cpu_type(cpu_type: c_uint);
@@ -35,25 +49,19 @@ extern "C" {
     pub fn m68k_pulse_reset();
     pub fn m68k_pulse_bus_error();
     pub fn m68k_execute(num_cycles: c_int) -> c_int;
+    pub fn m68k_cycles_run() -> c_int;
+    pub fn m68k_cycles_remaining() -> c_int;
+    pub fn m68k_modify_timeslice(cycles: c_int);
     pub fn m68k_disassemble(buf: *mut c_char, pc: c_uint, cpu_type: c_uint) -> c_uint;
     pub fn m68k_set_instr_hook_callback(hook: InstructionHook);
+    pub fn m68k_set_fc_callback(callback: FunctionCodeCallback);
+    pub fn m68k_set_int_ack_callback(callback: InterruptAckCallback);
     pub fn m68k_set_irq(int_level: c_uint);
+    pub fn m68k_get_reg(context: *mut c_void, reg: c_uint) -> c_uint;
 }
 
 pub struct Cpu {}
 
-// TODO: Interrupts.
-//
-// Levels:
-//    1: TIMER
-//    2: DMA
-//    3: SCSI
-//    4: SPARE
-//    5: UART
-//    6: VSYNC
-//    7: DEBUG
-//
-
 impl Cpu {
     pub fn new() -> Self {
         init();
@@ -61,8 +69,43 @@ impl Cpu {
         Cpu {}
     }
 
-    pub fn execute(&mut self, cycles: &u32) {
-        let _ = unsafe { m68k_execute(*cycles as c_int) };
+    /// Run for at least `cycles` cycles, finishing the instruction in
+    /// progress. Returns the number of cycles run.
+    pub fn execute(&mut self, cycles: u64) -> u64 {
+        EXECUTING.with(|e| e.set(true));
+        let ran = unsafe { m68k_execute(cycles as c_int) };
+        EXECUTING.with(|e| e.set(false));
+        ran as u64
+    }
+}
+
+thread_local! {
+    /// True while this thread is running a CPU time slice. Other
+    /// threads must leave Musashi's cycle counts alone.
+    static EXECUTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The number of cycles run so far in the current time slice
+pub fn cycles_run() -> u64 {
+    if EXECUTING.with(Cell::get) {
+        unsafe { m68k_cycles_run() as u64 }
+    } else {
+        0
+    }
+}
+
+/// End the current time slice once `cycles` more have run, if it
+/// would otherwise run on past them.
+pub fn end_slice_within(cycles: u64) {
+    if !EXECUTING.with(Cell::get) {
+        return;
+    }
+
+    unsafe {
+        let remaining = m68k_cycles_remaining() as i64;
+        if (cycles as i64) < remaining {
+            m68k_modify_timeslice((cycles as i64 - remaining) as c_int);
+        }
     }
 }
 
@@ -72,6 +115,43 @@ pub fn set_irq(ipl: u8) {
     }
 }
 
+/// The function code the CPU is driving, set by Musashi before
+/// every bus access.
+static FUNCTION_CODE: AtomicU8 = AtomicU8::new(FunctionCode::SUPERVISOR_PROGRAM.0);
+
+/// The function code of the access the CPU is making
+pub fn function_code() -> FunctionCode {
+    FunctionCode(FUNCTION_CODE.load(Ordering::Relaxed))
+}
+
+/// The program space for the CPU's current mode. The disassembler
+/// reads memory without the CPU driving a function code.
+pub fn program_space() -> FunctionCode {
+    let sr = unsafe { m68k_get_reg(std::ptr::null_mut(), M68K_REG_SR) };
+    if sr & SR_SUPERVISOR != 0 {
+        FunctionCode::SUPERVISOR_PROGRAM
+    } else {
+        FunctionCode::USER_PROGRAM
+    }
+}
+
+extern "C" fn set_function_code(fc: c_uint) {
+    FUNCTION_CODE.store(fc as u8 & 7, Ordering::Relaxed);
+}
+
+/// The interrupt acknowledge cycle. Devices that supply their own
+/// vector are asked for it; the rest are autovectored.
+extern "C" fn interrupt_ack(level: c_int) -> c_int {
+    FUNCTION_CODE.store(FunctionCode::CPU_SPACE.0, Ordering::Relaxed);
+    match BUS.lock().unwrap().acknowledge(level as u8) {
+        Some(vector) => {
+            trace!("IACK level={} vector={:02x}", level, vector);
+            vector as c_int
+        }
+        None => M68K_INT_ACK_AUTOVECTOR,
+    }
+}
+
 pub fn bus_error() {
     unsafe {
         m68k_pulse_bus_error();
@@ -84,6 +164,8 @@ fn init() {
         m68k_init();
         m68k_set_cpu_type(M68K_CPU_TYPE_68010);
         m68k_set_instr_hook_callback(instruction_hook);
+        m68k_set_fc_callback(set_function_code);
+        m68k_set_int_ack_callback(interrupt_ack);
     }
 }
 
@@ -99,7 +181,7 @@ extern "C" fn instruction_hook(pc: c_uint) {
         static mut C_ARR: [c_char; 256] = [0; 256];
 
         unsafe {
-            let c_ptr = C_ARR.as_mut_ptr();
+            let c_ptr = std::ptr::addr_of_mut!(C_ARR) as *mut c_char;
             m68k_disassemble(c_ptr, pc, M68K_CPU_TYPE_68010);
             trace!("{:08x}:    {}", pc, CStr::from_ptr(c_ptr).to_str().unwrap());
         }
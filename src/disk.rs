@@ -0,0 +1,505 @@
+//! SCSI direct-access (hard disk) target
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::err::SimError;
+use crate::overlay::{Overlay, OverlayMode};
+use crate::scsi::*;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::{debug, error, info};
+use num_traits::FromPrimitive;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Logical block size, in bytes
+pub const BLOCK_SIZE: usize = 512;
+
+/// Sectors per track reported in the Format Device mode page
+const SECTORS_PER_TRACK: u16 = 17;
+/// Heads reported in the Rigid Disk Geometry mode page
+const HEADS: u8 = 6;
+
+const INQUIRY_LEN: usize = 36;
+const VENDOR: &[u8; 8] = b"TEKTRONX";
+const PRODUCT: &[u8; 16] = b"4404 HARD DISK  ";
+const REVISION: &[u8; 4] = b"1.0 ";
+
+const PAGE_FORMAT: u8 = 0x03;
+const PAGE_GEOMETRY: u8 = 0x04;
+const PAGE_ALL: u8 = 0x3f;
+
+/// Drive geometry, as reported to the initiator through MODE SENSE.
+///
+/// The geometry is synthesized from the size of the image, since a
+/// raw image file carries no geometry of its own.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Geometry {
+    pub cylinders: u32,
+    pub heads: u8,
+    pub sectors: u16,
+}
+
+impl Geometry {
+    pub fn for_blocks(blocks: u32) -> Self {
+        let per_cyl = HEADS as u32 * SECTORS_PER_TRACK as u32;
+        Geometry {
+            cylinders: blocks.div_ceil(per_cyl),
+            heads: HEADS,
+            sectors: SECTORS_PER_TRACK,
+        }
+    }
+
+    /// The smallest geometry holding at least `bytes` bytes, up to
+    /// the most whole cylinders a 32-bit block address can reach
+    pub fn for_size(bytes: u64) -> Self {
+        let per_cyl = HEADS as u32 * SECTORS_PER_TRACK as u32;
+        let most = (u32::MAX / per_cyl * per_cyl) as u64;
+        let blocks = bytes.div_ceil(BLOCK_SIZE as u64).min(most);
+        Geometry::for_blocks(blocks as u32)
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors as u64
+    }
+}
+
+/// Parse an image size in bytes, with an optional `K`, `M`, or `G`
+/// suffix.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, scale) = match s.to_ascii_uppercase().chars().last() {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    match digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+    {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(format!("invalid size '{s}'")),
+    }
+}
+
+/// Create a blank image at `path` that fills `geometry` exactly, so
+/// the geometry reported by the target matches its capacity. An
+/// existing file is never overwritten.
+pub fn create(path: &str, geometry: &Geometry) -> Result<(), SimError> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|f| f.set_len(geometry.blocks() * BLOCK_SIZE as u64))
+        .map_err(|e| SimError::Io(format!("{path}: {e}")))
+}
+
+/// A hard disk backed by a raw image file on the host.
+pub struct Disk {
+    file: File,
+    blocks: u32,
+    read_only: bool,
+    /// Receives writes in place of the image, if present
+    overlay: Option<Overlay>,
+}
+
+/// The number of blocks in the image at `path`, which is `len` bytes
+/// long. Block numbers are 32 bits wide, which limits an image to just
+/// under 2 TiB.
+fn image_blocks(path: &str, len: u64) -> Result<u32, SimError> {
+    if len == 0 || !len.is_multiple_of(BLOCK_SIZE as u64) {
+        return Err(SimError::Init(format!(
+            "{path}: image size must be a non-zero multiple of {BLOCK_SIZE} bytes"
+        )));
+    }
+
+    u32::try_from(len / BLOCK_SIZE as u64).map_err(|_| {
+        SimError::Init(format!(
+            "{path}: image is too large, the limit is {} blocks",
+            u32::MAX
+        ))
+    })
+}
+
+impl Disk {
+    /// Open the image at `path`. The image is write-protected if
+    /// `read_only` is set, or if the host does not permit writing to
+    /// it. With an overlay, the image itself is only ever read, and
+    /// only `read_only` write-protects the disk.
+    pub fn open(
+        path: &str,
+        read_only: bool,
+        overlay: Option<&OverlayMode>,
+    ) -> Result<Disk, SimError> {
+        let writable = OpenOptions::new().read(true).write(true).open(path);
+        let (file, read_only) = match writable {
+            Ok(f) if !read_only && overlay.is_none() => (f, false),
+            _ if overlay.is_some() => match File::open(path) {
+                Ok(f) => (f, read_only),
+                Err(e) => return Err(SimError::Init(format!("{path}: {e}"))),
+            },
+            _ => match File::open(path) {
+                Ok(f) => (f, true),
+                Err(e) => return Err(SimError::Init(format!("{path}: {e}"))),
+            },
+        };
+
+        let len = file
+            .metadata()
+            .map_err(|e| SimError::Init(format!("{path}: {e}")))?
+            .len();
+
+        let blocks = image_blocks(path, len)?;
+        let overlay = match overlay {
+            Some(mode) => {
+                let overlay = Overlay::open(mode, blocks)?;
+                info!("{}: overlay holds {} blocks", path, overlay.count());
+                Some(overlay)
+            }
+            None => None,
+        };
+
+        Ok(Disk {
+            file,
+            blocks,
+            read_only,
+            overlay,
+        })
+    }
+
+    fn check_range(&self, lba: u32, count: u32) -> Result<(), Sense> {
+        if lba as u64 + count as u64 > self.blocks as u64 {
+            Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_blocks(&mut self, lba: u32, count: u32) -> Result<Vec<u8>, Sense> {
+        self.check_range(lba, count)?;
+
+        let mut buf = vec![0; count as usize * BLOCK_SIZE];
+        let result = match &mut self.overlay {
+            Some(overlay) => (lba..lba + count)
+                .zip(buf.chunks_mut(BLOCK_SIZE))
+                .try_for_each(|(n, block)| {
+                    if overlay.contains(n) {
+                        overlay.read(n, block)
+                    } else {
+                        self.file
+                            .seek(SeekFrom::Start(n as u64 * BLOCK_SIZE as u64))
+                            .and_then(|_| self.file.read_exact(block))
+                    }
+                }),
+            None => self
+                .file
+                .seek(SeekFrom::Start(lba as u64 * BLOCK_SIZE as u64))
+                .and_then(|_| self.file.read_exact(&mut buf)),
+        };
+
+        result.map_err(|e| {
+            error!("Disk read failed at LBA {}: {}", lba, e);
+            Sense::new(SENSE_MEDIUM_ERROR, ASC_UNRECOVERED_READ)
+        })?;
+
+        Ok(buf)
+    }
+
+    fn write_blocks(&mut self, lba: u32, data: &[u8]) -> Result<(), Sense> {
+        self.check_range(lba, (data.len() / BLOCK_SIZE) as u32)?;
+
+        if self.read_only {
+            return Err(Sense::new(SENSE_DATA_PROTECT, ASC_WRITE_PROTECTED));
+        }
+
+        let result = match &mut self.overlay {
+            Some(overlay) => (lba..)
+                .zip(data.chunks(BLOCK_SIZE))
+                .try_for_each(|(n, block)| overlay.write(n, block)),
+            None => self
+                .file
+                .seek(SeekFrom::Start(lba as u64 * BLOCK_SIZE as u64))
+                .and_then(|_| self.file.write_all(data)),
+        };
+
+        result.map_err(|e| {
+            error!("Disk write failed at LBA {}: {}", lba, e);
+            Sense::new(SENSE_MEDIUM_ERROR, ASC_WRITE_FAULT)
+        })
+    }
+
+    fn inquiry(&self, cdb: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; INQUIRY_LEN];
+        // Byte 0: Peripheral device type 0 (direct access)
+        buf[2] = 1; // SCSI-1
+        buf[3] = 1; // CCS response format
+        buf[4] = (INQUIRY_LEN - 5) as u8;
+        buf[8..16].copy_from_slice(VENDOR);
+        buf[16..32].copy_from_slice(PRODUCT);
+        buf[32..36].copy_from_slice(REVISION);
+        buf.truncate(cdb[4] as usize);
+        buf
+    }
+
+    fn read_capacity(&self) -> Vec<u8> {
+        let mut buf = vec![0; 8];
+        BigEndian::write_u32(&mut buf[0..4], self.blocks - 1);
+        BigEndian::write_u32(&mut buf[4..8], BLOCK_SIZE as u32);
+        buf
+    }
+
+    fn mode_sense(&self, cdb: &[u8]) -> Result<Vec<u8>, Sense> {
+        let page = cdb[2] & 0x3f;
+        let geometry = Geometry::for_blocks(self.blocks);
+
+        if !matches!(page, 0 | PAGE_FORMAT | PAGE_GEOMETRY | PAGE_ALL) {
+            return Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD));
+        }
+
+        // Mode parameter header, followed by one block descriptor.
+        let mut buf = vec![0; 12];
+        if self.read_only {
+            buf[2] = 0x80;
+        }
+        buf[3] = 8;
+        // The descriptor has room for 24 bits. Like a real SCSI-1
+        // drive, a larger disk reports as many blocks as fit, and
+        // READ CAPACITY gives the real number.
+        BigEndian::write_u24(&mut buf[5..8], self.blocks.min(0xffffff));
+        BigEndian::write_u24(&mut buf[9..12], BLOCK_SIZE as u32);
+
+        if page == PAGE_FORMAT || page == PAGE_ALL {
+            let mut p = [0; 24];
+            p[0] = PAGE_FORMAT;
+            p[1] = 22;
+            BigEndian::write_u16(&mut p[2..4], geometry.heads as u16);
+            BigEndian::write_u16(&mut p[10..12], geometry.sectors);
+            BigEndian::write_u16(&mut p[12..14], BLOCK_SIZE as u16);
+            BigEndian::write_u16(&mut p[14..16], 1);
+            p[20] = 0x40; // HSEC: hard sectored
+            buf.extend_from_slice(&p);
+        }
+
+        if page == PAGE_GEOMETRY || page == PAGE_ALL {
+            let mut p = [0; 24];
+            p[0] = PAGE_GEOMETRY;
+            p[1] = 22;
+            BigEndian::write_u24(&mut p[2..5], geometry.cylinders.min(0xffffff));
+            p[5] = geometry.heads;
+            buf.extend_from_slice(&p);
+        }
+
+        buf[0] = (buf.len() - 1) as u8;
+        buf.truncate(cdb[4] as usize);
+        Ok(buf)
+    }
+}
+
+/// Decode the LBA and block count of a READ or WRITE command
+pub fn rw_params(cdb: &[u8]) -> (u32, u32) {
+    if cdb[0] & 0xe0 == 0 {
+        let lba = BigEndian::read_u24(&cdb[1..4]) & 0x1fffff;
+        let count = match cdb[4] {
+            0 => 256,
+            n => n as u32,
+        };
+        (lba, count)
+    } else {
+        (
+            BigEndian::read_u32(&cdb[2..6]),
+            BigEndian::read_u16(&cdb[7..9]) as u32,
+        )
+    }
+}
+
+impl ScsiTarget for Disk {
+    fn command(&mut self, cdb: &[u8]) -> Result<Transfer, Sense> {
+        match FromPrimitive::from_u8(cdb[0]) {
+            Some(Op::TestReady)
+            | Some(Op::StartStop)
+            | Some(Op::PreventAllow)
+            | Some(Op::Reserve)
+            | Some(Op::Release)
+            | Some(Op::SendDiag) => Ok(Transfer::None),
+            Some(Op::Inquiry) => Ok(Transfer::In(self.inquiry(cdb))),
+            Some(Op::ReadCapacity) => Ok(Transfer::In(self.read_capacity())),
+            Some(Op::ModeSense6) => Ok(Transfer::In(self.mode_sense(cdb)?)),
+            Some(Op::Read6) | Some(Op::Read10) => {
+                let (lba, count) = rw_params(cdb);
+                debug!("READ lba={} count={}", lba, count);
+                Ok(Transfer::In(self.read_blocks(lba, count)?))
+            }
+            Some(Op::Write6) | Some(Op::Write10) => {
+                let (lba, count) = rw_params(cdb);
+                debug!("WRITE lba={} count={}", lba, count);
+                self.check_range(lba, count)?;
+                Ok(Transfer::Out(count as usize * BLOCK_SIZE))
+            }
+            _ => Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_OPCODE)),
+        }
+    }
+
+    fn data_out(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Sense> {
+        let (lba, _) = rw_params(cdb);
+        self.write_blocks(lba, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn with_disk<T>(name: &str, blocks: usize, test: T)
+    where
+        T: FnOnce(&mut Disk),
+    {
+        let path: PathBuf = std::env::temp_dir().join(format!("tek4404-{name}.img"));
+        std::fs::write(&path, vec![0; blocks * BLOCK_SIZE]).unwrap();
+        let mut disk = Disk::open(path.to_str().unwrap(), false, None).unwrap();
+        test(&mut disk);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_read_capacity() {
+        with_disk("capacity", 100, |disk| {
+            match disk.command(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]) {
+                Ok(Transfer::In(buf)) => assert_eq!(vec![0, 0, 0, 99, 0, 0, 2, 0], buf),
+                _ => panic!("bad read capacity response"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        with_disk("rw", 16, |disk| {
+            let write = [0x0a, 0, 0, 3, 1, 0];
+            match disk.command(&write) {
+                Ok(Transfer::Out(n)) => assert_eq!(BLOCK_SIZE, n),
+                _ => panic!("bad write response"),
+            }
+            disk.data_out(&write, &[0xa5; BLOCK_SIZE]).unwrap();
+
+            match disk.command(&[0x28, 0, 0, 0, 0, 3, 0, 0, 1, 0]) {
+                Ok(Transfer::In(buf)) => assert_eq!(vec![0xa5; BLOCK_SIZE], buf),
+                _ => panic!("bad read response"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_overlay_leaves_image_untouched() {
+        let path = std::env::temp_dir().join("tek4404-golden.img");
+        std::fs::write(&path, vec![0; 4 * BLOCK_SIZE]).unwrap();
+        let mut disk =
+            Disk::open(path.to_str().unwrap(), false, Some(&OverlayMode::Memory)).unwrap();
+
+        let write = [0x0a, 0, 0, 1, 1, 0];
+        disk.command(&write).unwrap();
+        disk.data_out(&write, &[0xa5; BLOCK_SIZE]).unwrap();
+
+        match disk.command(&[0x08, 0, 0, 0, 2, 0]) {
+            Ok(Transfer::In(buf)) => {
+                assert_eq!(vec![0; BLOCK_SIZE], buf[..BLOCK_SIZE]);
+                assert_eq!(vec![0xa5; BLOCK_SIZE], buf[BLOCK_SIZE..]);
+            }
+            _ => panic!("bad read response"),
+        }
+        assert_eq!(vec![0; 4 * BLOCK_SIZE], std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_create() {
+        let path = std::env::temp_dir().join("tek4404-create.img");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let geometry = Geometry::for_size(parse_size("45M").unwrap());
+        assert_eq!(904, geometry.cylinders);
+        create(path, &geometry).unwrap();
+        assert!(create(path, &geometry).is_err());
+
+        let disk = Disk::open(path, true, None).unwrap();
+        assert_eq!(geometry.blocks(), disk.blocks as u64);
+        assert_eq!(geometry, Geometry::for_blocks(disk.blocks));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_large_sizes() {
+        assert_eq!(Ok(8 << 30), parse_size("8G"));
+        assert!(parse_size("99999999999G").is_err());
+        assert!(parse_size("0K").is_err());
+
+        // The largest size gives a geometry whose blocks can all be
+        // addressed.
+        let geometry = Geometry::for_size(u64::MAX);
+        assert!(geometry.blocks() <= u32::MAX as u64);
+
+        // An 8 GiB disk has 2^24 blocks, one more than the MODE
+        // SENSE block descriptor can count. MODE SENSE never reads the
+        // image, so a small one stands in for it.
+        let geometry = Geometry::for_size(parse_size("8G").unwrap());
+        assert!(geometry.blocks() >= 1 << 24);
+        with_disk("8g", 16, |disk| {
+            disk.blocks = 1 << 24;
+            match disk.command(&[0x1a, 0, 0x3f, 0, 255, 0]) {
+                Ok(Transfer::In(buf)) => assert_eq!([0xff, 0xff, 0xff], buf[5..8]),
+                _ => panic!("bad mode sense response"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_image_blocks() {
+        let max = u32::MAX as u64 * BLOCK_SIZE as u64;
+        assert!(image_blocks("x", 0).is_err());
+        assert!(image_blocks("x", 513).is_err());
+        assert_eq!(Ok(u32::MAX), image_blocks("x", max));
+        assert!(image_blocks("x", max + BLOCK_SIZE as u64).is_err());
+        assert!(image_blocks("x", 1 << 41).is_err());
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        with_disk("range", 16, |disk| {
+            match disk.command(&[0x08, 0, 0, 15, 2, 0]) {
+                Err(sense) => assert_eq!(SENSE_ILLEGAL_REQUEST, sense.key),
+                _ => panic!("expected check condition"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_inquiry_allocation_length() {
+        with_disk("inquiry", 16, |disk| {
+            match disk.command(&[0x12, 0, 0, 0, 5, 0]) {
+                Ok(Transfer::In(buf)) => assert_eq!(vec![0, 0, 1, 1, 31], buf),
+                _ => panic!("bad inquiry response"),
+            }
+        });
+    }
+}
@@ -0,0 +1,153 @@
+//! SCSI DMA controller
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::bus::*;
+use crate::err::BusError;
+
+use log::{debug, error};
+
+// The DMA controller sits between the SCSI controller's data
+// register and main memory. The CPU loads the starting memory address
+// into the address register, then issues a SCSI Transfer Info command
+// with the DMA bit set. The controller moves bytes until the SCSI
+// transfer counter reaches zero or the target changes phase, then
+// sets DONE in its status register and requests a level 2 interrupt.
+//
+// The address register is from the original register map. The
+// control and status register and its bits are a provisional guess,
+// with no documentation or schematic behind them, made so that DMA
+// transfers can complete and interrupt.
+//
+// Registers:
+//
+//   0x7bc000: Address (long, or bytes shifted in MSB first)
+//   0x7bc004: Control (write) / Status (read)
+//
+// Status Register:
+//
+//   Bit 7: DONE   - A transfer has finished. Cleared by reading status.
+//   Bit 6: ERROR  - The transfer stopped on a memory access error.
+//   Bit 0: BUSY   - A transfer is in progress.
+//
+// Control Register:
+//
+//   Bit 0: IE     - Interrupt on completion
+
+const STAT_DONE: u8 = 0b10000000;
+const STAT_ERROR: u8 = 0b01000000;
+const STAT_BUSY: u8 = 0b00000001;
+
+const CTRL_IE: u8 = 0b00000001;
+
+pub struct Dma {
+    address: u32,
+    control: u8,
+    status: u8,
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma {
+            address: 0,
+            control: CTRL_IE,
+            status: 0,
+        }
+    }
+
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn set_address(&mut self, address: u32) {
+        debug!("DMA ADDRESS = {:08x}", address);
+        self.address = address & 0xffffff;
+    }
+
+    /// Byte writes shift into the address register, most significant
+    /// byte first.
+    pub fn shift_address(&mut self, value: u8) {
+        self.set_address((self.address << 8) | value as u32);
+    }
+
+    pub fn set_control(&mut self, value: u8) {
+        debug!("DMA CONTROL = {:02x}", value);
+        self.control = value;
+    }
+
+    /// Reading the status register acknowledges a finished transfer.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.status;
+        self.status &= !(STAT_DONE | STAT_ERROR);
+        status
+    }
+
+    pub fn busy(&self) -> bool {
+        self.status & STAT_BUSY != 0
+    }
+
+    /// True if the controller is requesting an interrupt.
+    pub fn interrupt(&self) -> bool {
+        self.status & STAT_DONE != 0 && self.control & CTRL_IE != 0
+    }
+
+    pub fn start(&mut self) {
+        debug!("DMA START: address={:08x}", self.address);
+        self.status = STAT_BUSY;
+    }
+
+    pub fn finish(&mut self, failed: bool) {
+        debug!("DMA DONE: address={:08x} failed={}", self.address, failed);
+        self.status = STAT_DONE;
+        if failed {
+            self.status |= STAT_ERROR;
+        }
+    }
+
+    /// Check that the next access falls in main memory. DMA can
+    /// never reach I/O space, which also keeps the controller from
+    /// re-entering the SCSI device while it is being serviced.
+    fn check(&self) -> Result<usize, BusError> {
+        let address = self.address as usize;
+        if address > RAM_END {
+            error!("DMA address out of range: {:08x}", address);
+            Err(BusError::Access)
+        } else {
+            Ok(address)
+        }
+    }
+
+    /// Fetch the next byte from memory, for transfer to the target.
+    pub fn read(&mut self, bus: &mut Bus) -> Result<u8, BusError> {
+        let value = bus.read_8(FunctionCode::SUPERVISOR_DATA, self.check()?)?;
+        self.address += 1;
+        Ok(value)
+    }
+
+    /// Store the next byte received from the target into memory.
+    pub fn write(&mut self, bus: &mut Bus, value: u8) -> Result<(), BusError> {
+        bus.write_8(FunctionCode::SUPERVISOR_DATA, self.check()?, value)?;
+        self.address += 1;
+        Ok(())
+    }
+}
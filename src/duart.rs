@@ -1,9 +1,8 @@
 //! Keyboard and RS-232 serial
 
-use sdl2::keyboard::Keycode;
-
 use crate::bus::*;
 use crate::err::*;
+use crate::irq::{self, Interrupt};
 
 use log::debug;
 use std::collections::VecDeque;
@@ -39,6 +38,7 @@ const MR12B: usize = 0x7b4010;
 const CSRB: usize = 0x7b4012;
 const CRB: usize = 0x7b4014;
 const THRB: usize = 0x7b4016;
+const IVR: usize = 0x7b4018;
 const IP_OPCR: usize = 0x7b401a;
 const OPBITS_SET: usize = 0x7b401c;
 const OPBITS_RESET: usize = 0x7b401e;
@@ -77,11 +77,9 @@ const ISTS_RBI: u8 = 0x20;
 const ISTS_IPC: u8 = 0x80;
 
 //
-// Interrupt Masks
+// Interrupt Vector Register, at reset
 //
-const KEYBOARD_INT: u8 = 0x04;
-const TX_INT: u8 = 0x10;
-const RX_INT: u8 = 0x20;
+const IVR_RESET: u8 = 0x0f;
 
 #[allow(dead_code)]
 struct Port {
@@ -126,7 +124,7 @@ pub struct Duart {
     outprt: u8,
     istat: u8,
     imr: u8,
-    ivec: u8,
+    ivr: u8,
 }
 
 // NOTES:
@@ -143,82 +141,6 @@ pub struct Duart {
 // Input Port 4: Keyboard Ready. The keyboard asserts IP4 HIGH when
 // ready to receive a command.
 
-// TODO: This map is incomplete, and it's been derived by
-// trial-and-error.
-fn map_keycode(k: &Keycode) -> u8 {
-    match *k {
-        Keycode::LShift => 0x01,
-        Keycode::RShift => 0x02,
-        Keycode::Return => 0x05,
-        Keycode::Backspace => 0x06,
-        Keycode::Tab => 0x07,
-        Keycode::Escape => 0x0a,
-        Keycode::Space => 0x0b,
-        Keycode::Quote => 0x0c,
-        Keycode::Comma => 0x0d,
-        Keycode::Minus => 0x0e,
-        Keycode::Period => 0x0f,
-        Keycode::Slash => 0x10,
-        Keycode::Num0 => 0x11,
-        Keycode::Num1 => 0x12,
-        Keycode::Num2 => 0x13,
-        Keycode::Num3 => 0x14,
-        Keycode::Num4 => 0x15,
-        Keycode::Num5 => 0x16,
-        Keycode::Num6 => 0x17,
-        Keycode::Num7 => 0x18,
-        Keycode::Num8 => 0x19,
-        Keycode::Num9 => 0x1a,
-        Keycode::Semicolon => 0x1b,
-        Keycode::Equals => 0x1c,
-        Keycode::A => 0x1d,
-        Keycode::B => 0x1e,
-        Keycode::C => 0x1f,
-        Keycode::D => 0x20,
-        Keycode::E => 0x21,
-        Keycode::F => 0x22,
-        Keycode::G => 0x23,
-        Keycode::H => 0x24,
-        Keycode::I => 0x25,
-        Keycode::J => 0x26,
-        Keycode::K => 0x27,
-        Keycode::L => 0x28,
-        Keycode::M => 0x29,
-        Keycode::N => 0x2a,
-        Keycode::O => 0x2b,
-        Keycode::P => 0x2c,
-        Keycode::Q => 0x2d,
-        Keycode::R => 0x2e,
-        Keycode::S => 0x2f,
-        Keycode::T => 0x30,
-        Keycode::U => 0x31,
-        Keycode::V => 0x32,
-        Keycode::W => 0x33,
-        Keycode::X => 0x34,
-        Keycode::Y => 0x35,
-        Keycode::Z => 0x36,
-        Keycode::LeftBracket => 0x37,
-        Keycode::Backslash => 0x38,
-        Keycode::RightBracket => 0x39,
-        Keycode::Delete => 0x3b,
-        Keycode::KpEnter => 0x3c,
-        Keycode::KpComma => 0x3d,
-        Keycode::KpMinus => 0x3e,
-        Keycode::KpPeriod => 0x3f,
-        Keycode::Kp0 => 0x40,
-        Keycode::Kp1 => 0x41,
-        Keycode::Kp2 => 0x42,
-        Keycode::Kp3 => 0x43,
-        Keycode::Kp4 => 0x44,
-        Keycode::Kp5 => 0x45,
-        Keycode::Kp6 => 0x46,
-        Keycode::Kp7 => 0x47,
-        Keycode::Kp8 => 0x48,
-        Keycode::Kp9 => 0x49,
-        _ => 0x03,
-    }
-}
-
 impl Duart {
     pub fn new() -> Duart {
         Duart {
@@ -229,43 +151,51 @@ impl Duart {
             outprt: 0,
             istat: 0,
             imr: 0,
-            ivec: 0,
+            ivr: IVR_RESET,
         }
     }
 
-    pub fn key_down(&mut self, k: &Keycode) {
-        let c = map_keycode(k);
+    /// Send the key down code for key `code` from the keyboard.
+    pub fn key_down(&mut self, code: u8) {
+        let c = code;
         debug!("Key Down: {:02x}", c);
         let ctx = &mut self.ports[PORT_A];
 
         if (ctx.conf & CNF_ERX) != 0 {
             ctx.stat |= STS_RXR;
             self.istat |= ISTS_RAI;
-            self.ivec |= RX_INT;
             ctx.rx_queue.push_front(c);
         }
+        self.update_irq();
     }
 
-    pub fn key_up(&mut self, k: &Keycode) {
-        let c = map_keycode(k) | 0x80;
+    /// Send the key up code for key `code` from the keyboard.
+    pub fn key_up(&mut self, code: u8) {
+        let c = code | 0x80;
         debug!("Key Up: {:02x}", c);
         let ctx = &mut self.ports[PORT_A];
 
         if (ctx.conf & CNF_ERX) != 0 {
             ctx.stat |= STS_RXR;
             self.istat |= ISTS_RAI;
-            self.ivec |= RX_INT;
             ctx.rx_queue.push_front(c);
         }
+        self.update_irq();
+    }
+
+    /// The DUART requests an interrupt whenever an unmasked condition
+    /// is set in the interrupt status register.
+    fn update_irq(&self) {
+        irq::set(Interrupt::Uart, self.istat & self.imr != 0);
     }
 
     #[allow(dead_code)]
     fn handle_rx(&mut self, port: usize) {
         let ctx = &mut self.ports[port];
 
-        let (istat, ivec) = match port {
-            0 => (ISTS_RAI, RX_INT),
-            _ => (ISTS_RBI, KEYBOARD_INT),
+        let istat = match port {
+            0 => ISTS_RAI,
+            _ => ISTS_RBI,
         };
 
         if let Some(c) = ctx.rx_queue.pop_back() {
@@ -273,7 +203,6 @@ impl Duart {
                 ctx.rx_data = c;
                 ctx.stat |= STS_RXR;
                 self.istat |= istat;
-                self.ivec |= ivec;
             }
         }
     }
@@ -292,16 +221,11 @@ impl Duart {
             ctx.stat |= STS_TXR;
             ctx.stat |= STS_TXE;
             self.istat |= tx_istat;
-            // Only RS232 transmit generates an interrupt.
-            if port == PORT_B {
-                self.ivec |= TX_INT;
-            }
             if (ctx.mode[1] >> 6) & 3 == 0x2 {
                 // Loopback Mode.
                 ctx.rx_data = c;
                 ctx.stat |= STS_RXR;
                 self.istat |= rx_istat;
-                self.ivec |= RX_INT;
             } else {
                 ctx.tx_queue.push_front(c);
             }
@@ -323,7 +247,6 @@ impl Duart {
             ctx.stat &= !STS_TXR;
             ctx.stat &= !STS_TXE;
             if port == PORT_A {
-                self.ivec &= !TX_INT;
                 self.istat &= !ISTS_TAI;
             }
         } else if cmd & CMD_ETX != 0 {
@@ -332,7 +255,6 @@ impl Duart {
             ctx.stat |= STS_TXE;
             if port == PORT_A {
                 self.istat |= ISTS_TAI;
-                self.ivec |= TX_INT;
             }
         }
 
@@ -341,10 +263,8 @@ impl Duart {
             ctx.conf &= !CNF_ERX;
             ctx.stat &= !STS_RXR;
             if port == PORT_A {
-                self.ivec &= !RX_INT;
                 self.istat &= !ISTS_RAI;
             } else {
-                self.ivec &= !KEYBOARD_INT;
                 self.istat &= !ISTS_RBI;
             }
         } else if cmd & CMD_ERX != 0 {
@@ -370,8 +290,8 @@ impl Duart {
     }
 }
 
-impl IoDevice for Duart {
-    fn read_8(&mut self, _bus: &mut Bus, address: usize) -> Result<u8, BusError> {
+impl Duart {
+    fn read_register(&mut self, address: usize) -> Result<u8, BusError> {
         match address {
             MR12A => {
                 let ctx = &mut self.ports[PORT_A];
@@ -393,14 +313,12 @@ impl IoDevice for Duart {
                 if ctx.rx_queue.is_empty() {
                     ctx.stat &= !STS_RXR;
                     self.istat &= !ISTS_RAI;
-                    self.ivec &= !RX_INT;
                 }
                 Ok(ctx.rx_data)
             }
             IPCR_ACR => {
                 let result = self.ipcr;
                 self.ipcr &= !0x0f;
-                self.ivec = 0;
                 self.istat &= !ISTS_IPC;
                 debug!("[READ]: IPCR_ACR: val={:02x}", result);
                 Ok(result)
@@ -424,10 +342,13 @@ impl IoDevice for Duart {
                 let ctx = &mut self.ports[PORT_B];
                 ctx.stat &= !STS_RXR;
                 self.istat &= !ISTS_RBI;
-                self.ivec &= !KEYBOARD_INT;
                 debug!("[READ]: THRB: val={:02x}", ctx.rx_data);
                 Ok(ctx.rx_data)
             }
+            IVR => {
+                debug!("[READ]: IVR: val={:02x}", self.ivr);
+                Ok(self.ivr)
+            }
             IP_OPCR => {
                 debug!("[READ]: IP_OPCR: val={:02x}", self.inprt);
                 Ok(self.inprt)
@@ -439,23 +360,7 @@ impl IoDevice for Duart {
         }
     }
 
-    fn read_16(&mut self, bus: &mut Bus, address: usize) -> Result<u16, BusError> {
-        match address {
-            MR12A => {
-                let ctx = &self.ports[PORT_A];
-                let lo: u16 = ctx.mode[0] as u16;
-                let hi: u16 = (ctx.mode[1] as u16) << 8;
-                debug!("[READ16]: MR12A: val={:02x}", hi | lo);
-                Ok(hi | lo)
-            }
-            _ => {
-                let b = self.read_8(bus, address)?;
-                Ok(b as u16)
-            }
-        }
-    }
-
-    fn write_8(&mut self, _bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
+    fn write_register(&mut self, address: usize, value: u8) {
         match address {
             MR12A => {
                 let ctx = &mut self.ports[PORT_A];
@@ -487,7 +392,6 @@ impl IoDevice for Duart {
                 // transmit will happen in the 'service' function.
                 ctx.stat &= !(STS_TXE | STS_TXR);
                 self.istat &= !ISTS_TAI;
-                self.ivec &= !TX_INT;
                 debug!("[WRITE]: THRA: val={:02x}", value);
             }
             IPCR_ACR => {
@@ -524,6 +428,10 @@ impl IoDevice for Duart {
 
                 debug!("[WRITE]: THRB: val={:02x}", value);
             }
+            IVR => {
+                self.ivr = value;
+                debug!("[WRITE]: IVR: val={:02x}", value);
+            }
             IP_OPCR => {
                 debug!("[WRITE]: IP_OPCR: val={:02x}", value);
             }
@@ -547,7 +455,40 @@ impl IoDevice for Duart {
                 debug!("[WRITE]: UNHANDLED: addr={:08x} val={:02x}", address, value);
             }
         }
+    }
+}
+
+impl IoDevice for Duart {
+    fn read_8(&mut self, _bus: &mut Bus, address: usize) -> Result<u8, BusError> {
+        let result = self.read_register(address);
+        self.update_irq();
+        result
+    }
 
+    fn read_16(&mut self, bus: &mut Bus, address: usize) -> Result<u16, BusError> {
+        match address {
+            MR12A => {
+                let ctx = &self.ports[PORT_A];
+                let lo: u16 = ctx.mode[0] as u16;
+                let hi: u16 = (ctx.mode[1] as u16) << 8;
+                debug!("[READ16]: MR12A: val={:02x}", hi | lo);
+                Ok(hi | lo)
+            }
+            _ => {
+                let b = self.read_8(bus, address)?;
+                Ok(b as u16)
+            }
+        }
+    }
+
+    fn write_8(&mut self, _bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
+        self.write_register(address, value);
+        self.update_irq();
         Ok(())
     }
+
+    /// The DUART supplies the vector in its IVR.
+    fn vector(&mut self) -> Option<u8> {
+        Some(self.ivr)
+    }
 }
@@ -0,0 +1,133 @@
+//! The emulator thread
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::bus::{BUS, QUEUE};
+use crate::cpu::Cpu;
+use crate::duart::Duart;
+use crate::frontend::Input;
+use crate::throttle::{self, Speed, Throttle};
+
+use log::info;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+// The CPU runs on a thread of its own, so that execution never holds
+// up the display or the network servers, and they never hold up
+// execution. Everything else talks to it by message. Messages are
+// picked up between time slices, which are short enough that input
+// is not noticeably delayed.
+
+/// A message to the emulator thread
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Input from the frontend
+    Input(Input),
+    /// Stop running and end the thread
+    Quit,
+}
+
+struct Emulator {
+    cpu: Cpu,
+    throttle: Throttle,
+    duart: Arc<Mutex<Duart>>,
+    messages: Receiver<Message>,
+}
+
+impl Emulator {
+    fn run(&mut self) {
+        loop {
+            loop {
+                match self.messages.try_recv() {
+                    Ok(Message::Input(input)) => self.input(input),
+                    // Losing every sender is as good as being told to quit.
+                    Ok(Message::Quit) | Err(TryRecvError::Disconnected) => return,
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            let run = self.slice();
+            let delay = self.throttle.pace(run, Instant::now());
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    fn input(&mut self, input: Input) {
+        match input {
+            Input::KeyDown(code) => self.duart.lock().unwrap().key_down(code),
+            Input::KeyUp(code) => self.duart.lock().unwrap().key_up(code),
+        }
+    }
+
+    /// Run one time slice, returning the number of cycles run.
+    fn slice(&mut self) -> u64 {
+        // Run the CPU up to each pending service request in turn, so
+        // that it is serviced on time.
+        let mut run = 0;
+        while run < throttle::SLICE {
+            loop {
+                // Hold the Queue lock for as brief a time as possible
+                // by assigning the result of `take()` to a variable.
+                let next_task = QUEUE.lock().unwrap().take();
+
+                if let Some(srq) = next_task {
+                    BUS.lock().unwrap().service(srq);
+                } else {
+                    break;
+                }
+            }
+
+            let next = QUEUE.lock().unwrap().until_next();
+            let remaining = throttle::SLICE - run;
+            let slice = next.unwrap_or(remaining).clamp(1, remaining);
+            let ran = self.cpu.execute(slice);
+            QUEUE.lock().unwrap().advance(ran);
+            run += ran;
+        }
+        run
+    }
+}
+
+/// Start the CPU running on a new thread, at `speed`. The bus must
+/// already be populated.
+pub fn spawn(speed: Speed, duart: Arc<Mutex<Duart>>) -> (Sender<Message>, JoinHandle<()>) {
+    let (sender, messages) = mpsc::channel();
+    let handle = thread::Builder::new()
+        .name(String::from("cpu"))
+        .spawn(move || {
+            let mut emulator = Emulator {
+                cpu: Cpu::new(),
+                throttle: Throttle::new(speed),
+                duart,
+                messages,
+            };
+            emulator.run();
+            info!("CPU stopped");
+        })
+        .expect("Could not start the CPU thread");
+    (sender, handle)
+}
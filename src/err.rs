@@ -69,12 +69,14 @@ impl Error for BusError {
 #[derive(PartialEq, Eq)]
 pub enum SimError {
     Init(String),
+    Io(String),
 }
 
 impl fmt::Debug for SimError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SimError::Init(s) => write!(f, "Initialization Error: {s}"),
+            SimError::Io(s) => write!(f, "I/O Error: {s}"),
         }
     }
 }
@@ -83,6 +85,7 @@ impl fmt::Display for SimError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SimError::Init(s) => write!(f, "Initialization Error: {s}"),
+            SimError::Io(s) => write!(f, "I/O Error: {s}"),
         }
     }
 }
@@ -91,6 +94,7 @@ impl Error for SimError {
     fn description(&self) -> &str {
         match *self {
             SimError::Init(_) => "Initialization Error",
+            SimError::Io(_) => "I/O Error",
         }
     }
 
@@ -0,0 +1,267 @@
+//! SCSI fault injection
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::disk::rw_params;
+use crate::err::SimError;
+use crate::scsi::*;
+
+use log::info;
+use num_traits::FromPrimitive;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Faults are read from a file with one rule per line. Each rule
+// names the SCSI ID it applies to, the fault, and its arguments.
+// Blank lines and anything following a '#' are ignored.
+//
+//   ID medium-error LBA[-LBA]   Reads or writes touching these blocks
+//                               fail with MEDIUM ERROR
+//   ID unit-attention           Report UNIT ATTENTION after power on
+//                               and every controller reset
+//   ID busy                     Answer commands with BUSY status
+//   ID timeout                  Ignore selection
+//   ID slow MICROSECONDS        Take longer to execute each command
+//
+// Any rule but unit-attention may end with `times=N`, after which it
+// stops firing. Without it, the rule fires every time.
+
+/// A fault the target can be made to exhibit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    MediumError { first: u32, last: u32 },
+    UnitAttention,
+    Busy,
+    SelectionTimeout,
+    Slow(Duration),
+}
+
+/// A fault to inject into the target at SCSI ID `id`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FaultRule {
+    pub id: usize,
+    pub fault: Fault,
+    /// The number of times left to fire, or None for no limit
+    pub times: Option<u32>,
+}
+
+impl FaultRule {
+    /// Count one firing, returning false once the rule is spent.
+    fn fire(&mut self) -> bool {
+        match &mut self.times {
+            Some(0) => false,
+            Some(n) => {
+                *n -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl FromStr for FaultRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words: Vec<&str> = s.split_whitespace().collect();
+
+        let times = match words.last().and_then(|w| w.strip_prefix("times=")) {
+            Some(n) => {
+                words.pop();
+                Some(n.parse().map_err(|_| format!("bad count '{n}'"))?)
+            }
+            None => None,
+        };
+
+        let (id, name, args) = match words.as_slice() {
+            [id, name, args @ ..] => (id, *name, args),
+            _ => return Err(format!("expected ID FAULT [ARGS], got '{s}'")),
+        };
+
+        let id = match id.parse::<usize>() {
+            Ok(id) if id < MAX_TARGETS - 1 => id,
+            _ => return Err(format!("bad SCSI ID '{id}'")),
+        };
+
+        let number = |arg: &str| {
+            arg.parse::<u32>()
+                .map_err(|_| format!("bad argument '{arg}' for {name}"))
+        };
+
+        let fault = match (name, args) {
+            ("medium-error", [range]) => {
+                let (first, last) = match range.split_once('-') {
+                    Some((first, last)) => (number(first)?, number(last)?),
+                    None => (number(range)?, number(range)?),
+                };
+                if last < first {
+                    return Err(format!("bad LBA range '{range}'"));
+                }
+                Fault::MediumError { first, last }
+            }
+            ("unit-attention", []) if times.is_none() => Fault::UnitAttention,
+            ("busy", []) => Fault::Busy,
+            ("timeout", []) => Fault::SelectionTimeout,
+            ("slow", [us]) => Fault::Slow(Duration::from_micros(number(us)? as u64)),
+            _ => return Err(format!("bad fault rule '{s}'")),
+        };
+
+        Ok(FaultRule { id, fault, times })
+    }
+}
+
+/// Read the fault rules in the file at `path`.
+pub fn load(path: &str) -> Result<Vec<FaultRule>, SimError> {
+    let text = std::fs::read_to_string(path).map_err(|e| SimError::Init(format!("{path}: {e}")))?;
+
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if !line.is_empty() {
+            let rule = line
+                .parse()
+                .map_err(|e| SimError::Init(format!("{}:{}: {}", path, n + 1, e)))?;
+            rules.push(rule);
+        }
+    }
+
+    info!("Loaded {} SCSI fault rules from {}", rules.len(), path);
+    Ok(rules)
+}
+
+/// The faults injected into one target
+#[derive(Default)]
+pub struct Faults {
+    rules: Vec<FaultRule>,
+}
+
+impl Faults {
+    pub fn add(&mut self, rule: FaultRule) {
+        self.rules.push(rule);
+    }
+
+    /// Fire the first live rule matching `matches`.
+    fn fire<F>(&mut self, matches: F) -> Option<Fault>
+    where
+        F: Fn(&Fault) -> bool,
+    {
+        self.rules
+            .iter_mut()
+            .filter(|r| matches(&r.fault))
+            .find_map(|r| if r.fire() { Some(r.fault) } else { None })
+    }
+
+    pub fn unit_attention(&self) -> bool {
+        self.rules.iter().any(|r| r.fault == Fault::UnitAttention)
+    }
+
+    pub fn busy(&mut self) -> bool {
+        self.fire(|f| *f == Fault::Busy).is_some()
+    }
+
+    pub fn selection_timeout(&mut self) -> bool {
+        self.fire(|f| *f == Fault::SelectionTimeout).is_some()
+    }
+
+    /// Extra time to take executing a command
+    pub fn delay(&mut self) -> Duration {
+        match self.fire(|f| matches!(f, Fault::Slow(_))) {
+            Some(Fault::Slow(delay)) => delay,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// A medium error for a read or write touching a bad block. The
+    /// sense information field holds the first bad block.
+    pub fn medium_error(&mut self, cdb: &[u8]) -> Option<Sense> {
+        let asc = match FromPrimitive::from_u8(cdb[0]) {
+            Some(Op::Read6) | Some(Op::Read10) => ASC_UNRECOVERED_READ,
+            Some(Op::Write6) | Some(Op::Write10) => ASC_WRITE_FAULT,
+            _ => return None,
+        };
+
+        let (lba, count) = rw_params(cdb);
+        let end = lba as u64 + count as u64;
+        let fault = self.fire(|f| match *f {
+            Fault::MediumError { first, last } => (first as u64) < end && last >= lba,
+            _ => false,
+        })?;
+
+        match fault {
+            Fault::MediumError { first, .. } => Some(Sense {
+                info: Some(first.max(lba)),
+                ..Sense::new(SENSE_MEDIUM_ERROR, asc)
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(
+            Ok(FaultRule {
+                id: 0,
+                fault: Fault::MediumError {
+                    first: 100,
+                    last: 107
+                },
+                times: Some(2),
+            }),
+            "0 medium-error 100-107 times=2".parse()
+        );
+        assert_eq!(
+            Ok(FaultRule {
+                id: 3,
+                fault: Fault::Slow(Duration::from_micros(5000)),
+                times: None,
+            }),
+            "3 slow 5000".parse()
+        );
+        assert!("7 busy".parse::<FaultRule>().is_err());
+        assert!("0 medium-error 9-8".parse::<FaultRule>().is_err());
+        assert!("0 unit-attention times=1".parse::<FaultRule>().is_err());
+    }
+
+    #[test]
+    fn test_rules_wear_out() {
+        let mut faults = Faults::default();
+        faults.add("0 busy times=2".parse().unwrap());
+        faults.add("0 medium-error 10 times=1".parse().unwrap());
+
+        assert!(faults.busy());
+        assert!(faults.busy());
+        assert!(!faults.busy());
+
+        // A read of blocks 8-11 covers block 10.
+        let read = [0x08, 0, 0, 8, 4, 0];
+        let sense = faults.medium_error(&read).unwrap();
+        assert_eq!(SENSE_MEDIUM_ERROR, sense.key);
+        assert_eq!(Some(10), sense.info);
+        assert_eq!(None, faults.medium_error(&read));
+    }
+}
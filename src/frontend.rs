@@ -0,0 +1,167 @@
+//! Display and input frontends
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::bus::{VideoDevice, VideoRamDevice};
+use crate::emulator::Message;
+use crate::err::SimError;
+#[cfg(feature = "sdl")]
+use crate::sdl::Sdl;
+use crate::video::Screen;
+
+use std::ops::Range;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+// A frontend is whatever stands in for the 4404's screen and
+// keyboard: a window on the host, for example. It is shown frames
+// as the picture changes, and passes on what the user does in the
+// 4404's own terms, so that nothing past this point needs to know
+// what kind of frontend it is.
+
+/// The number of milliseconds to idle between framebuffer repaints
+const DISPLAY_IDLE: u64 = 10;
+
+/// Something the user did
+// Only frontends make input, and a build without SDL has none.
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// A key pressed, by its 4404 keyboard code
+    KeyDown(u8),
+    /// A key released
+    KeyUp(u8),
+}
+
+pub trait Frontend {
+    /// Show the screen. Only the rows in `changed` differ from the
+    /// last time it was shown.
+    fn frame(&mut self, screen: &Screen, changed: Range<usize>);
+
+    /// Collect the user's input since the last poll, or None once the
+    /// user has closed the frontend.
+    fn poll(&mut self) -> Option<Vec<Input>>;
+}
+
+/// Open the frontend this build has, or None if it has none and must
+/// run headless.
+pub fn create() -> Result<Option<Box<dyn Frontend>>, SimError> {
+    #[cfg(feature = "sdl")]
+    let frontend: Option<Box<dyn Frontend>> = Some(Box::new(Sdl::new()?));
+    #[cfg(not(feature = "sdl"))]
+    let frontend = None;
+    Ok(frontend)
+}
+
+/// Bring the screen up to date with video RAM, returning the rows
+/// that changed.
+fn update_screen(
+    screen: &mut Screen,
+    video: &VideoDevice,
+    vram: &VideoRamDevice,
+) -> Option<Range<usize>> {
+    let video = video.lock().unwrap();
+    let result = screen.update(&video, &mut vram.lock().unwrap());
+    result
+}
+
+/// Run `frontend` until the user closes it, showing it the display
+/// and sending its input to the CPU.
+pub fn run(
+    frontend: &mut dyn Frontend,
+    video: &VideoDevice,
+    video_ram: &VideoRamDevice,
+    cpu: &Sender<Message>,
+) {
+    let sleep_time = Duration::from_millis(DISPLAY_IDLE);
+    let mut screen = Screen::new();
+
+    while let Some(input) = frontend.poll() {
+        for input in input {
+            // A failed send means the CPU thread has already stopped.
+            let _ = cpu.send(Message::Input(input));
+        }
+
+        if let Some(rows) = update_screen(&mut screen, video, video_ram) {
+            frontend.frame(&screen, rows);
+        }
+
+        thread::sleep(sleep_time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::{Video, DISPLAY_HEIGHT};
+    use crate::vram::VideoRam;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    /// A frontend that types one key, then closes
+    struct Script {
+        polls: usize,
+        frames: Vec<Range<usize>>,
+    }
+
+    impl Frontend for Script {
+        fn frame(&mut self, _screen: &Screen, changed: Range<usize>) {
+            self.frames.push(changed);
+        }
+
+        fn poll(&mut self) -> Option<Vec<Input>> {
+            self.polls += 1;
+            match self.polls {
+                1 => Some(vec![Input::KeyDown(0x1d), Input::KeyUp(0x1d)]),
+                2 => Some(Vec::new()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let video = Arc::new(Mutex::new(Video::new()));
+        let video_ram = Arc::new(Mutex::new(VideoRam::new()));
+        let (cpu, messages) = mpsc::channel();
+        let mut script = Script {
+            polls: 0,
+            frames: Vec::new(),
+        };
+
+        run(&mut script, &video, &video_ram, &cpu);
+
+        // The first frame is drawn in full, and with nothing changing
+        // there are no more.
+        assert_eq!(vec![0..DISPLAY_HEIGHT], script.frames);
+        let input: Vec<Message> = messages.try_iter().collect();
+        assert_eq!(
+            vec![
+                Message::Input(Input::KeyDown(0x1d)),
+                Message::Input(Input::KeyUp(0x1d))
+            ],
+            input
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Interrupt controller
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::cpu;
+
+use log::trace;
+use num_derive::FromPrimitive;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+// Each interrupting device on the 4404 has its own request line,
+// wired to a fixed level. The CPU sees the highest level with a
+// request asserted. A device asserts its line while it has an
+// interrupt condition pending and deasserts it once the condition
+// has been acknowledged, however the device defines that.
+
+/// The interrupt request lines, by the level each one requests.
+/// Not every line has a device driving it yet.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum Interrupt {
+    Timer = 1,
+    Dma = 2,
+    Scsi = 3,
+    Spare = 4,
+    Uart = 5,
+    Vsync = 6,
+    Debug = 7,
+}
+
+impl Interrupt {
+    pub fn level(self) -> u8 {
+        self as u8
+    }
+}
+
+pub struct InterruptController {
+    /// Asserted request lines, one bit per level
+    pending: u8,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController { pending: 0 }
+    }
+
+    /// Assert or deassert a request line.
+    pub fn set(&mut self, irq: Interrupt, active: bool) {
+        if active {
+            self.pending |= 1 << irq.level();
+        } else {
+            self.pending &= !(1 << irq.level());
+        }
+    }
+
+    /// The highest level requested, or 0 if none is.
+    pub fn level(&self) -> u8 {
+        match self.pending {
+            0 => 0,
+            pending => 7 - pending.leading_zeros() as u8,
+        }
+    }
+}
+
+/// The interrupt controller shared by every device. Like the bus,
+/// it must be global so the CPU can be told of changes from wherever
+/// a device happens to be running.
+pub static INTC: Lazy<Mutex<InterruptController>> =
+    Lazy::new(|| Mutex::new(InterruptController::new()));
+
+/// Assert or deassert a request line, and present the highest
+/// requested level to the CPU.
+pub fn set(irq: Interrupt, active: bool) {
+    let mut intc = INTC.lock().unwrap();
+    let level = intc.level();
+    intc.set(irq, active);
+    if intc.level() != level {
+        trace!("IPL {} -> {} ({:?}={})", level, intc.level(), irq, active);
+        cpu::set_irq(intc.level());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highest_level_wins() {
+        let mut intc = InterruptController::new();
+        assert_eq!(0, intc.level());
+
+        intc.set(Interrupt::Dma, true);
+        intc.set(Interrupt::Uart, true);
+        intc.set(Interrupt::Scsi, true);
+        assert_eq!(5, intc.level());
+
+        // Clearing one line leaves the others requesting.
+        intc.set(Interrupt::Uart, false);
+        assert_eq!(3, intc.level());
+        intc.set(Interrupt::Scsi, false);
+        assert_eq!(2, intc.level());
+        intc.set(Interrupt::Dma, false);
+        assert_eq!(0, intc.level());
+    }
+}
@@ -35,17 +35,34 @@ mod acia;
 mod bus;
 mod cal;
 mod cpu;
+mod disk;
+mod dma;
 mod duart;
+mod emulator;
 mod err;
+mod fault;
 mod fpu;
+mod frontend;
+mod irq;
 mod mem;
 mod mmu;
+mod monitor;
 mod mouse;
+mod overlay;
 mod scsi;
+#[cfg(feature = "sdl")]
+mod sdl;
 mod service;
 mod sound;
+mod tape;
+mod throttle;
 mod timer;
+mod trace;
+// Shared with tek4404-fs, which uses the parts the emulator does not.
+#[allow(dead_code)]
+mod uniflex;
 mod video;
+mod vram;
 
 extern crate num_derive;
 extern crate strum;
@@ -53,35 +70,21 @@ extern crate strum_macros;
 
 use acia::{Acia, AciaServer, AciaState};
 use bus::*;
-use cpu::Cpu;
 use duart::Duart;
+use emulator::Message;
 use log::info;
 use mem::Memory;
-use scsi::Scsi;
-use service::ServiceKey;
+use monitor::Monitor;
+use scsi::{Scsi, TargetConfig};
+use throttle::Speed;
 use video::Video;
+use vram::VideoRam;
 
-use clap::Parser;
-use tokio::time;
+use clap::{Parser, Subcommand};
 
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 
-use sdl2::event::Event;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::rect::Rect;
-
-/// Framebuffer width
-const FB_WIDTH: u32 = 1024;
-/// Framebuffer height
-const FB_HEIGHT: u32 = 1024;
-/// Visible window width
-const WINDOW_WIDTH: u32 = 640;
-/// Visible window height
-const WINDOW_HEIGHT: u32 = 480;
-/// The number of milliseconds to idle between framebuffer repaints
-const DISPLAY_IDLE: u64 = 10;
-
 /// Clap options parsed from the command line
 #[derive(Parser, Debug)]
 #[clap(about = "Tektronix 4404 Emulator")]
@@ -95,59 +98,110 @@ struct Opts {
     /// The port to bind the debug ACIA telnet server to
     #[clap(short, long, default_value = "9090", help = "Port to bind to")]
     port: String,
-    /// The number of CPU steps to take on each loop
-    #[clap(
-        short,
-        long,
-        default_value = "30000",
-        help = "CPU execution steps per loop"
-    )]
-    steps: u32,
-    /// The number of machine cycles to execute each step
+    /// The port to bind the debugging monitor to
+    #[clap(long, help = "Port to bind the debugging monitor to")]
+    monitor: Option<String>,
+    /// The guest speed, as a percentage of the real 4404's
     #[clap(
-        short,
         long,
-        default_value = "16",
-        help = "CPU cycles per execution step"
+        default_value = "100",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Run at a percentage of real speed"
     )]
-    cycles: u32,
-    /// The amount of time to idle between loops
-    #[clap(
-        short,
-        long,
-        default_value = "25",
-        help = "Idle time between CPU loops (in ms)"
-    )]
-    idle: u64,
+    speed: u32,
+    /// Run the guest as fast as the host allows
+    #[clap(long, conflicts_with = "speed", help = "Run as fast as possible")]
+    turbo: bool,
+    /// Run without a display or keyboard
+    #[clap(long, help = "Run without a display")]
+    headless: bool,
+    /// SCSI targets to attach, as ID:TYPE:PATH (may be repeated)
+    #[clap(long, help = "Attach a SCSI target, as ID:TYPE:PATH")]
+    scsi: Vec<TargetConfig>,
+    /// A file of faults to inject into SCSI targets
+    #[clap(long, help = "Inject SCSI faults listed in a file")]
+    scsi_faults: Option<String>,
+    /// A file to write a decoded trace of SCSI transactions to
+    #[clap(long, help = "Write a trace of SCSI transactions to a file")]
+    scsi_trace: Option<String>,
+    /// A utility to run instead of the emulator
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-/// Update the framebuffer vector based on current state of Video RAM
-//
-// TODO: It makes much more sense to implement a special memory device
-//       for video RAM that reads and writes each pixel as an RGB332 byte,
-//       then we don't need this expensive step.
-fn update_framebuffer(vm: &MemoryDevice, fb: &mut [u8]) {
-    let mut index: usize = 0;
-    let mem = &vm.lock().unwrap().mem;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage disk images
+    #[clap(subcommand)]
+    Disk(DiskCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum DiskCommand {
+    /// Create a blank disk image
+    Create {
+        /// The image to create
+        image: String,
+        /// The capacity, in bytes, with an optional K, M, or G suffix.
+        /// It is rounded up to a whole number of cylinders.
+        #[clap(short, long, default_value = "45M", value_parser = disk::parse_size)]
+        size: u64,
+        /// Write an empty Uniflex filesystem to the image
+        #[clap(short, long)]
+        format: bool,
+        /// The filesystem name, when formatting
+        #[clap(short, long, default_value = "tek4404")]
+        name: String,
+    },
+    /// Copy the blocks held in an overlay back into its base image
+    Commit {
+        /// The base disk image
+        image: String,
+        /// The sidecar overlay file
+        overlay: String,
+    },
+}
 
-    for b in mem {
-        for i in 0..=7 {
-            if (b >> (7 - i)) & 1 == 1 {
-                fb[index] = 0;
-            } else {
-                fb[index] = 255;
+fn disk_command(command: &DiskCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        DiskCommand::Create {
+            image,
+            size,
+            format,
+            name,
+        } => {
+            let geometry = disk::Geometry::for_size(*size);
+            disk::create(image, &geometry)?;
+            println!(
+                "{}: {} cylinders, {} heads, {} sectors ({} blocks)",
+                image,
+                geometry.cylinders,
+                geometry.heads,
+                geometry.sectors,
+                geometry.blocks()
+            );
+            if *format {
+                let fs = uniflex::Filesystem::format(image, geometry.blocks() as u32, name)?;
+                println!("{}: {} blocks free", image, fs.free_blocks());
             }
-            index += 1;
+        }
+        DiskCommand::Commit { image, overlay } => {
+            let count = overlay::commit(image, overlay)?;
+            println!("Committed {count} blocks from {overlay} to {image}");
         }
     }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     let opts: Opts = Opts::parse();
 
     env_logger::init();
 
+    if let Some(Command::Disk(command)) = &opts.command {
+        return disk_command(command);
+    }
+
     info!("INITIALIZING");
 
     // Load the ROM boot file.
@@ -161,14 +215,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let ram = Arc::new(Mutex::new(
         Memory::new(RAM_START, RAM_END, RAM_SIZE, false).unwrap(),
     ));
-    let video_ram = Arc::new(Mutex::new(
-        Memory::new(VRAM_START, VRAM_END, VRAM_SIZE, false).unwrap(),
-    ));
+    let video_ram = Arc::new(Mutex::new(VideoRam::new()));
     let acia_state = Arc::new(Mutex::new(AciaState::new()));
     let acia = Arc::new(Mutex::new(Acia::new(acia_state.clone())));
     let video = Arc::new(Mutex::new(Video::new()));
     let duart = Arc::new(Mutex::new(Duart::new()));
-    let scsi = Arc::new(Mutex::new(Scsi::new()));
+    let mut scsi = Scsi::new();
+    for target in &opts.scsi {
+        scsi.attach(target.id, target.open()?)?;
+    }
+    if let Some(path) = &opts.scsi_trace {
+        scsi.set_trace(trace::Trace::create(path)?);
+    }
+    if let Some(path) = &opts.scsi_faults {
+        for rule in fault::load(path)? {
+            scsi.add_fault(rule)?;
+        }
+    }
+    let scsi = Arc::new(Mutex::new(scsi));
 
     // Populate the global bus (this is done in a block so that
     // the bus lock can be dropped immediately)
@@ -178,111 +242,55 @@ async fn main() -> Result<(), Box<dyn Error>> {
         // The bus can own these devices
         bus.rom = Some(rom);
         bus.ram = Some(ram);
-        bus.video = Some(video);
 
         // The bus must share these devices
         bus.acia = Some(acia.clone());
+        bus.video = Some(video.clone());
         bus.video_ram = Some(video_ram.clone());
         bus.duart = Some(duart.clone());
         bus.scsi = Some(scsi.clone());
     }
+    video.lock().unwrap().start();
 
-    let mut cpu = Cpu::new();
-
-    loop {
-        tokio::join!(
-            async {
-                let sleep_time = time::Duration::from_millis(opts.idle);
-                loop {
-                    for _ in 0..opts.steps {
-                        cpu.execute(&opts.cycles);
-                    }
-
-                    loop {
-                        // Hold the Queue lock for as brief a time as possible
-                        // by assigning the result of `take()` to a variable.
-                        let next_task = QUEUE.lock().unwrap().take();
-
-                        if let Some(srq) = next_task {
-                            match srq.key {
-                                ServiceKey::Scsi => scsi.lock().unwrap().service(),
-                            }
-                        } else {
-                            break;
-                        }
-                    }
-
-                    time::sleep(sleep_time).await;
-                }
-            },
-            AciaServer::run(
-                acia_state.clone(),
-                opts.address.as_str(),
-                opts.port.as_str()
-            ),
-            async {
-                let sleep_time = time::Duration::from_millis(DISPLAY_IDLE);
-                let sdl_context = sdl2::init().expect("Could not initialize SDL2");
-                let video_subsystem = sdl_context.video().expect("Could not get video subsystem");
-
-                let window = video_subsystem
-                    .window("Tektronix 4404", WINDOW_WIDTH, WINDOW_HEIGHT)
-                    .build()
-                    .unwrap();
+    // Open the display before the CPU starts, so that a failure
+    // stops the emulator cleanly.
+    let display = if opts.headless {
+        None
+    } else {
+        frontend::create()?
+    };
 
-                let mut fb: Vec<u8> = vec![0; (FB_WIDTH * FB_HEIGHT) as usize];
-                let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-                let texture_creator = canvas.texture_creator();
-                let mut texture = texture_creator
-                    .create_texture_target(PixelFormatEnum::RGB332, FB_WIDTH, FB_HEIGHT)
-                    .expect("Unable to create texture");
+    let speed = if opts.turbo {
+        Speed::Turbo
+    } else {
+        Speed::Percent(opts.speed)
+    };
+    let (cpu, cpu_thread) = emulator::spawn(speed, duart);
 
-                let mut event_pump = sdl_context.event_pump().unwrap();
-
-                loop {
-                    for event in event_pump.poll_iter() {
-                        match event {
-                            Event::Quit { .. } => {
-                                info!("Good bye.");
-                                std::process::exit(0);
-                            }
-                            Event::KeyDown {
-                                keycode: Some(k), ..
-                            } => {
-                                duart.lock().unwrap().key_down(&k);
-                            }
-                            Event::KeyUp {
-                                keycode: Some(k), ..
-                            } => {
-                                duart.lock().unwrap().key_up(&k);
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    update_framebuffer(&video_ram, &mut fb);
-                    texture
-                        .update(None, &fb, FB_WIDTH as usize)
-                        .expect("Couldn't copy framebuffer to texture");
-
-                    canvas.clear();
-                    canvas
-                        .copy(
-                            &texture,
-                            // TODO: Texture source rectangle will
-                            // actually be controlled by framebuffer
-                            // panning register. It contains a 16-bit
-                            // offset into the VRAM where drawing is
-                            // to begin.
-                            Rect::new(0, 0, WINDOW_WIDTH, WINDOW_HEIGHT),
-                            None,
-                        )
-                        .expect("Couldn't copy texture to canvas.");
-                    canvas.present();
+    // The network servers run on tokio's threads, out of the way of
+    // the CPU and the display.
+    let runtime = tokio::runtime::Runtime::new()?;
+    let (address, port) = (opts.address.clone(), opts.port.clone());
+    runtime.spawn(async move { AciaServer::run(acia_state, &address, &port).await });
+    if let Some(port) = opts.monitor.clone() {
+        let address = opts.address.clone();
+        runtime.spawn(async move { Monitor::run(&address, &port).await });
+    }
 
-                    time::sleep(sleep_time).await;
-                }
-            }
-        );
+    match display {
+        Some(mut display) => {
+            frontend::run(display.as_mut(), &video, &video_ram, &cpu);
+            let _ = cpu.send(Message::Quit);
+            let _ = cpu_thread.join();
+            info!("Good bye.");
+        }
+        None => {
+            // Nothing tells the CPU to stop, so it runs until the
+            // process is killed.
+            info!("Running headless");
+            let _ = cpu_thread.join();
+        }
     }
+
+    Ok(())
 }
@@ -25,44 +25,443 @@
 use crate::bus::*;
 use crate::err::BusError;
 
-use log::debug;
+use log::{debug, info};
 
-pub struct Mmu {}
+// The MMU maps the 8MB user address space onto physical memory in
+// 4KB pages. Bits 12-22 of a user address select one of 2048 entries
+// in the page table, and the entry supplies bits 12-22 of the
+// physical address. Supervisor accesses are never translated.
+//
+// A user access to a page that is not valid, or a write to a page
+// that is not writable, is a page fault. The MMU records the fault in
+// its fault registers and the CPU takes a bus error. The bus error
+// frame lets the 68010 restart the instruction with RTE once the
+// page has been brought in.
+//
+// The page table is read and written as a 2Kx16 RAM from 0x800000
+// to 0xffffff. An entry is selected by bits 12-22 of the address, so
+// each entry appears at every word of its own 4KB window. While VM
+// is turned on, the table may still be read, but the only write
+// allowed is one that clears REF or MOD bits. Anything else is a bus
+// error.
+//
+// The layout below is provisional. The control register, the fault
+// registers and the bits of a page table entry are a guess, not
+// taken from any Tektronix documentation or schematic, and may be
+// wrong.
+//
+// Registers:
+//
+//   0x780000: Control (byte)
+//   0x780002: Fault Status (word, read only)
+//   0x780004: Fault Address (long, read only)
+//
+// Control Register:
+//
+//   Bit 0: VM     - Translate user addresses through the page table
+//
+// Fault Status Register:
+//
+//   Bit 4:      PROT   - The page is valid but not writable
+//   Bit 3:      WRITE  - The access was a write
+//   Bits 0-2:   FC     - Function code of the access
+//
+// The MMU sets REF and MOD as user accesses are translated. They
+// are only ever cleared by software, by rewriting the entry.
+//
+// The fault registers hold the most recent page fault. The fault
+// address is the user address, before translation.
+//
+// Page Table Entry:
+//
+//   Bit 15:     MOD    - The page has been written
+//   Bit 14:     REF    - The page has been accessed
+//   Bit 13:     VALID  - The page is mapped
+//   Bit 12:     WRITE  - The page may be written
+//   Bits 0-10:  FRAME  - Physical page number
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_COUNT: usize = 2048;
+
+const OFFSET_MASK: usize = (1 << PAGE_SHIFT) - 1;
+
+const ADDR_CONTROL: usize = MMU_START;
+const ADDR_FAULT_STATUS: usize = MMU_START + 2;
+const ADDR_FAULT_ADDRESS: usize = MMU_START + 4;
+
+const CTRL_VM: u8 = 0b00000001;
+
+pub const PTE_MOD: u16 = 0x8000;
+pub const PTE_REF: u16 = 0x4000;
+pub const PTE_VALID: u16 = 0x2000;
+pub const PTE_WRITE: u16 = 0x1000;
+pub const PTE_FRAME: u16 = 0x07ff;
+
+pub const FAULT_PROT: u16 = 0b00010000;
+pub const FAULT_WRITE: u16 = 0b00001000;
+
+pub struct Mmu {
+    control: u8,
+    table: Vec<u16>,
+    fault_status: u16,
+    fault_address: u32,
+}
+
+/// The page table entry selected by bits 12-22 of `address`
+fn page(address: usize) -> usize {
+    (address >> PAGE_SHIFT) & (PAGE_COUNT - 1)
+}
 
 impl Mmu {
     pub fn new() -> Self {
-        Mmu {}
+        Mmu {
+            control: 0,
+            table: vec![0; PAGE_COUNT],
+            fault_status: 0,
+            fault_address: 0,
+        }
+    }
+
+    pub fn vm_enabled(&self) -> bool {
+        self.control & CTRL_VM != 0
+    }
+
+    /// Translate a user address to a physical address.
+    pub fn translate(
+        &mut self,
+        address: usize,
+        fc: FunctionCode,
+        write: bool,
+    ) -> Result<usize, BusError> {
+        if !self.vm_enabled() {
+            return Ok(address);
+        }
+
+        match self.lookup(address, write) {
+            Some(physical) => {
+                self.table[page(address)] |= if write { PTE_REF | PTE_MOD } else { PTE_REF };
+                Ok(physical)
+            }
+            None => {
+                self.fault(address, self.table[page(address)], fc, write);
+                Err(BusError::Access)
+            }
+        }
+    }
+
+    /// Translate a user address without recording the access: no
+    /// REF or MOD bits are set and no fault is latched. For the
+    /// debugger, which must not disturb the paging software.
+    pub fn lookup(&self, address: usize, write: bool) -> Option<usize> {
+        if !self.vm_enabled() {
+            return Some(address);
+        }
+
+        let entry = self.table[page(address)];
+        if entry & PTE_VALID == 0 || (write && entry & PTE_WRITE == 0) {
+            return None;
+        }
+
+        Some(((entry & PTE_FRAME) as usize) << PAGE_SHIFT | (address & OFFSET_MASK))
+    }
+
+    /// A listing of the page table entries in use, one per line.
+    pub fn dump(&self) -> Vec<String> {
+        let flag = |entry: u16, bit: u16, c: char| if entry & bit != 0 { c } else { '-' };
+
+        let mut lines = vec![format!(
+            "VM {}, fault status={:02x} address={:08x}",
+            if self.vm_enabled() { "on" } else { "off" },
+            self.fault_status,
+            self.fault_address
+        )];
+        lines.push(String::from("PAGE  VIRTUAL   PHYSICAL  FLAGS"));
+
+        let (mut valid, mut referenced, mut modified) = (0, 0, 0);
+        for (page, entry) in self.table.iter().enumerate().filter(|(_, e)| **e != 0) {
+            lines.push(format!(
+                "{:4}  {:08x}  {:08x}  {}{}{}{}",
+                page,
+                page << PAGE_SHIFT,
+                ((entry & PTE_FRAME) as usize) << PAGE_SHIFT,
+                flag(*entry, PTE_VALID, 'V'),
+                flag(*entry, PTE_WRITE, 'W'),
+                flag(*entry, PTE_REF, 'R'),
+                flag(*entry, PTE_MOD, 'M')
+            ));
+            valid += (entry & PTE_VALID != 0) as usize;
+            referenced += (entry & PTE_REF != 0) as usize;
+            modified += (entry & PTE_MOD != 0) as usize;
+        }
+
+        lines.push(format!(
+            "{valid} valid, {referenced} referenced, {modified} modified"
+        ));
+        lines
+    }
+
+    /// Record a page fault in the fault registers.
+    fn fault(&mut self, address: usize, entry: u16, fc: FunctionCode, write: bool) {
+        let mut status = fc.0 as u16;
+        if write {
+            status |= FAULT_WRITE;
+        }
+        if entry & PTE_VALID != 0 {
+            status |= FAULT_PROT;
+        }
+        debug!(
+            "MMU: page fault at {:08x} in {} (entry={:04x} status={:02x})",
+            address, fc, entry, status
+        );
+        self.fault_status = status;
+        self.fault_address = address as u32;
+    }
+
+    fn read_register(&self, address: usize) -> u8 {
+        match address {
+            ADDR_CONTROL => self.control,
+            a if (ADDR_FAULT_STATUS..ADDR_FAULT_STATUS + 2).contains(&a) => {
+                self.fault_status.to_be_bytes()[address - ADDR_FAULT_STATUS]
+            }
+            a if (ADDR_FAULT_ADDRESS..ADDR_FAULT_ADDRESS + 4).contains(&a) => {
+                self.fault_address.to_be_bytes()[address - ADDR_FAULT_ADDRESS]
+            }
+            _ => {
+                debug!("(READ 8) addr={:08x}", address);
+                0
+            }
+        }
+    }
+
+    /// The page table entry for `address`, which must be in the page
+    /// table window.
+    fn entry(&self, address: usize) -> u16 {
+        self.table[page(address)]
+    }
+
+    /// Rewrite the page table entry for `address`. With VM on, only
+    /// REF and MOD bits may change, and only from set to clear, so
+    /// that software can track page use without turning VM off.
+    fn set_entry(&mut self, address: usize, value: u16) -> Result<(), BusError> {
+        let entry = self.table[page(address)];
+        let changed = value ^ entry;
+        if self.vm_enabled() && changed & !(entry & (PTE_REF | PTE_MOD)) != 0 {
+            debug!(
+                "MMU: page table write with VM on: {:08x} {:04x}->{:04x}",
+                address, entry, value
+            );
+            return Err(BusError::Access);
+        }
+        self.table[page(address)] = value;
+        Ok(())
+    }
+
+    fn write_control(&mut self, value: u8) {
+        if (value ^ self.control) & CTRL_VM != 0 {
+            info!(
+                "MMU: VM {}",
+                if value & CTRL_VM != 0 { "on" } else { "off" }
+            );
+        }
+        self.control = value;
     }
 }
 
 impl IoDevice for Mmu {
     fn read_8(&mut self, _bus: &mut Bus, address: usize) -> Result<u8, BusError> {
-        debug!("(READ 8) addr={:08x}", address);
-        Ok(0)
+        match address {
+            PT_START..=PT_END => {
+                let entry = self.entry(address);
+                Ok(if address & 1 == 0 {
+                    (entry >> 8) as u8
+                } else {
+                    entry as u8
+                })
+            }
+            _ => Ok(self.read_register(address)),
+        }
     }
 
-    fn read_16(&mut self, _bus: &mut Bus, address: usize) -> Result<u16, BusError> {
-        debug!("(READ 16) addr={:08x}", address);
-        Ok(0)
+    fn read_16(&mut self, bus: &mut Bus, address: usize) -> Result<u16, BusError> {
+        match address {
+            PT_START..=PT_END => Ok(self.entry(address)),
+            _ => Ok(
+                (self.read_8(bus, address)? as u16) << 8 | self.read_8(bus, address + 1)? as u16
+            ),
+        }
     }
 
-    fn read_32(&mut self, _bus: &mut Bus, address: usize) -> Result<u32, BusError> {
-        debug!("(READ 32) addr={:08x}", address);
-        Ok(0)
+    fn read_32(&mut self, bus: &mut Bus, address: usize) -> Result<u32, BusError> {
+        Ok((self.read_16(bus, address)? as u32) << 16 | self.read_16(bus, address + 2)? as u32)
     }
 
     fn write_8(&mut self, _bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
-        debug!("(WRITE 8) addr={:08x} val={:02x}", address, value);
+        match address {
+            PT_START..=PT_END => {
+                let entry = self.entry(address);
+                self.set_entry(
+                    address,
+                    if address & 1 == 0 {
+                        (entry & 0x00ff) | (value as u16) << 8
+                    } else {
+                        (entry & 0xff00) | value as u16
+                    },
+                )?;
+            }
+            ADDR_CONTROL => self.write_control(value),
+            _ => debug!("(WRITE 8) addr={:08x} val={:02x}", address, value),
+        }
         Ok(())
     }
 
-    fn write_16(&mut self, _bus: &mut Bus, address: usize, value: u16) -> Result<(), BusError> {
-        debug!("(WRITE 16) addr={:08x} val={:04x}", address, value);
-        Ok(())
+    fn write_16(&mut self, bus: &mut Bus, address: usize, value: u16) -> Result<(), BusError> {
+        match address {
+            PT_START..=PT_END => self.set_entry(address, value),
+            _ => {
+                self.write_8(bus, address, (value >> 8) as u8)?;
+                self.write_8(bus, address + 1, value as u8)
+            }
+        }
     }
 
-    fn write_32(&mut self, _bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
-        debug!("(WRITE 32) addr={:08x} val={:08x}", address, value);
-        Ok(())
+    fn write_32(&mut self, bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
+        self.write_16(bus, address, (value >> 16) as u16)?;
+        self.write_16(bus, address + 2, value as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: FunctionCode = FunctionCode::USER_DATA;
+
+    #[test]
+    fn test_page_table_window() {
+        let mut bus = Bus::new();
+        let mut mmu = Mmu::new();
+
+        mmu.write_16(&mut bus, PT_START + (5 << PAGE_SHIFT), 0x2123)
+            .unwrap();
+        assert_eq!(0x2123, mmu.table[5]);
+        // Every word of the 4KB window addresses the same entry.
+        assert_eq!(0x2123, mmu.read_16(&mut bus, PT_START + 0x5ffe).unwrap());
+        assert_eq!(0x21, mmu.read_8(&mut bus, PT_START + 0x5000).unwrap());
+        assert_eq!(0x23, mmu.read_8(&mut bus, PT_START + 0x5001).unwrap());
+
+        // With VM on the table can be read, but not rewritten.
+        mmu.write_8(&mut bus, ADDR_CONTROL, CTRL_VM).unwrap();
+        assert_eq!(Ok(0x2123), mmu.read_16(&mut bus, PT_START + 0x5000));
+        assert_eq!(
+            Err(BusError::Access),
+            mmu.write_16(&mut bus, PT_START + 0x5000, 0x2124)
+        );
+        assert_eq!(0x2123, mmu.table[5]);
+    }
+
+    #[test]
+    fn test_clear_ref_mod_with_vm_on() {
+        let mut bus = Bus::new();
+        let mut mmu = Mmu::new();
+        mmu.table[2] = PTE_VALID | PTE_WRITE | 0x41;
+        mmu.table[3] = PTE_VALID | PTE_WRITE | 0x42;
+        mmu.control = CTRL_VM;
+        mmu.translate(0x2000, USER, true).unwrap();
+        mmu.translate(0x3000, USER, true).unwrap();
+
+        // Clearing REF and MOD is allowed, a word or a byte at a time.
+        let entry = mmu.read_16(&mut bus, PT_START + 0x2000).unwrap();
+        assert_eq!(PTE_MOD | PTE_REF, entry & (PTE_MOD | PTE_REF));
+        mmu.write_16(&mut bus, PT_START + 0x2000, entry & !PTE_REF)
+            .unwrap();
+        assert_eq!(PTE_MOD | PTE_VALID | PTE_WRITE | 0x41, mmu.table[2]);
+        mmu.write_8(&mut bus, PT_START + 0x3000, 0x30).unwrap();
+        assert_eq!(PTE_VALID | PTE_WRITE | 0x42, mmu.table[3]);
+
+        // Setting them, or clearing them along with anything else, is not.
+        assert_eq!(
+            Err(BusError::Access),
+            mmu.write_16(
+                &mut bus,
+                PT_START + 0x3000,
+                PTE_REF | PTE_VALID | PTE_WRITE | 0x42
+            )
+        );
+        assert_eq!(
+            Err(BusError::Access),
+            mmu.write_16(&mut bus, PT_START + 0x2000, PTE_VALID | 0x41)
+        );
+        assert_eq!(PTE_MOD | PTE_VALID | PTE_WRITE | 0x41, mmu.table[2]);
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut mmu = Mmu::new();
+        mmu.table[1] = PTE_VALID | 0x40;
+        mmu.table[2] = PTE_VALID | PTE_WRITE | 0x41;
+
+        // Untranslated until VM is turned on
+        assert_eq!(Ok(0x1234), mmu.translate(0x1234, USER, true));
+
+        mmu.control = CTRL_VM;
+        assert_eq!(Ok(0x40234), mmu.translate(0x1234, USER, false));
+        assert_eq!(PTE_VALID | PTE_REF | 0x40, mmu.table[1]);
+        assert_eq!(Err(BusError::Access), mmu.translate(0x1234, USER, true));
+        assert_eq!(Ok(0x41ffe), mmu.translate(0x2ffe, USER, true));
+        assert_eq!(
+            PTE_VALID | PTE_WRITE | PTE_REF | PTE_MOD | 0x41,
+            mmu.table[2]
+        );
+        assert_eq!(Err(BusError::Access), mmu.translate(0x3000, USER, false));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut mmu = Mmu::new();
+        mmu.table[1] = PTE_VALID | 0x40;
+        mmu.control = CTRL_VM;
+
+        assert_eq!(Some(0x40234), mmu.lookup(0x1234, false));
+        assert_eq!(None, mmu.lookup(0x1234, true));
+        assert_eq!(None, mmu.lookup(0x3000, false));
+
+        // Nothing is recorded
+        assert_eq!(PTE_VALID | 0x40, mmu.table[1]);
+        assert_eq!(0, mmu.fault_status);
+        assert_eq!(0, mmu.fault_address);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut mmu = Mmu::new();
+        mmu.table[2] = PTE_VALID | PTE_WRITE | 0x41;
+        mmu.table[7] = PTE_VALID | 0x10;
+        mmu.control = CTRL_VM;
+        mmu.translate(0x2000, USER, true).unwrap();
+
+        let lines = mmu.dump();
+        assert_eq!("   2  00002000  00041000  VWRM", lines[2]);
+        assert_eq!("   7  00007000  00010000  V---", lines[3]);
+        assert_eq!("2 valid, 1 referenced, 1 modified", lines[4]);
+    }
+
+    #[test]
+    fn test_fault_registers() {
+        let mut bus = Bus::new();
+        let mut mmu = Mmu::new();
+        mmu.table[1] = PTE_VALID | 0x40;
+        mmu.control = CTRL_VM;
+
+        let program = FunctionCode::USER_PROGRAM;
+        assert_eq!(Err(BusError::Access), mmu.translate(0x5678, program, false));
+        assert_eq!(2, mmu.read_16(&mut bus, ADDR_FAULT_STATUS).unwrap());
+        assert_eq!(0x5678, mmu.read_32(&mut bus, ADDR_FAULT_ADDRESS).unwrap());
+
+        assert_eq!(Err(BusError::Access), mmu.translate(0x1ffe, USER, true));
+        assert_eq!(
+            1 | FAULT_WRITE | FAULT_PROT,
+            mmu.read_16(&mut bus, ADDR_FAULT_STATUS).unwrap()
+        );
+        assert_eq!(0x1f, mmu.read_8(&mut bus, ADDR_FAULT_ADDRESS + 2).unwrap());
     }
 }
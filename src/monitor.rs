@@ -0,0 +1,110 @@
+//! Debugging monitor
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::bus::*;
+
+use log::{error, info};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// The monitor is a line-oriented console for inspecting the machine
+// while it runs. Connect to it with telnet or netcat and type `help`.
+
+const HELP: &str = "\
+help         Show this help
+pages        Show the MMU page table entries in use, with their flags
+quit         Close the connection
+";
+
+/// Run a monitor command, returning its output
+fn command(line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => String::new(),
+        ["help"] => String::from(HELP),
+        ["pages"] => {
+            let mmu = BUS.lock().unwrap().mmu.clone();
+            match mmu {
+                Some(mmu) => mmu.lock().unwrap().dump().join("\n") + "\n",
+                None => String::from("No MMU\n"),
+            }
+        }
+        _ => format!("Unknown command: {line}\n"),
+    }
+}
+
+pub struct Monitor {}
+
+impl Monitor {
+    pub async fn run(bind: &str, port: &str) {
+        let addr = format!("{bind}:{port}");
+
+        info!("Listening for monitor connections on {}", addr);
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(
+                    "Unable to listen for monitor connections on {}: {}",
+                    addr, e
+                );
+                return;
+            }
+        };
+
+        loop {
+            let (socket, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Unable to accept monitor connection: {}", e);
+                    continue;
+                }
+            };
+            info!("Monitor connection from {}", peer);
+            tokio::spawn(async move {
+                if let Err(e) = Monitor::process(socket).await {
+                    error!("Monitor connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn process(socket: TcpStream) -> std::io::Result<()> {
+        let (reader, mut writer) = socket.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"tek4404> ").await?;
+        while let Some(line) = lines.next_line().await? {
+            let line = line.trim();
+            if line == "quit" {
+                break;
+            }
+            writer
+                .write_all(command(line).replace('\n', "\r\n").as_bytes())
+                .await?;
+            writer.write_all(b"tek4404> ").await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! Copy-on-write overlay for disk images
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::disk::BLOCK_SIZE;
+use crate::err::SimError;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::info;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// An overlay sits in front of a base disk image. Blocks written by
+// the 4404 land in the overlay, and reads of those blocks are served
+// from it, so the base image is never modified. The overlay lives
+// either in memory, where it is discarded at exit, or in a sidecar
+// file that persists between runs and can be committed back into the
+// base image.
+//
+// Sidecar layout:
+//
+//   Block 0:  Header. Magic, then the base image size in blocks
+//             (32-bit big-endian).
+//   Block 1+: Bitmap of blocks present in the overlay, one bit per
+//             block, MSB first, padded to a whole number of blocks.
+//   Data:     Block n of the image, at the data offset + n * 512.
+//
+// The data area is sized to hold every block of the base image, but
+// only blocks that have been written take up space on the host.
+
+const MAGIC: &[u8; 16] = b"TEK4404 OVERLAY\0";
+
+/// Where an overlay keeps the blocks written to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OverlayMode {
+    /// Kept in memory and discarded at exit
+    Memory,
+    /// Kept in a sparse sidecar file at the given path
+    Sidecar(String),
+}
+
+pub struct Overlay {
+    file: Option<File>,
+    blocks: u32,
+    bitmap: Vec<u8>,
+    memory: HashMap<u32, Vec<u8>>,
+}
+
+impl Overlay {
+    /// Open an overlay for a base image of `blocks` blocks. A sidecar
+    /// file is created if it does not already exist.
+    pub fn open(mode: &OverlayMode, blocks: u32) -> Result<Overlay, SimError> {
+        let mut overlay = Overlay {
+            file: None,
+            blocks,
+            bitmap: vec![0; bitmap_len(blocks)],
+            memory: HashMap::new(),
+        };
+
+        if let OverlayMode::Sidecar(path) = mode {
+            let err = |e: io::Error| SimError::Init(format!("{path}: {e}"));
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(err)?;
+
+            if file.metadata().map_err(err)?.len() == 0 {
+                info!("Creating overlay {} for {} blocks", path, blocks);
+                let mut header = vec![0; BLOCK_SIZE];
+                header[0..16].copy_from_slice(MAGIC);
+                BigEndian::write_u32(&mut header[16..20], blocks);
+                file.write_all(&header).map_err(err)?;
+                file.write_all(&overlay.bitmap).map_err(err)?;
+                file.set_len(overlay.data_offset(blocks)).map_err(err)?;
+            } else {
+                let mut header = vec![0; BLOCK_SIZE];
+                file.read_exact(&mut header).map_err(err)?;
+                if &header[0..16] != MAGIC {
+                    return Err(SimError::Init(format!("{path}: not a disk overlay")));
+                }
+                let size = BigEndian::read_u32(&header[16..20]);
+                if size != blocks {
+                    return Err(SimError::Init(format!(
+                        "{path}: overlay is for a {size} block image, not {blocks} blocks"
+                    )));
+                }
+                file.read_exact(&mut overlay.bitmap).map_err(err)?;
+            }
+
+            overlay.file = Some(file);
+        }
+
+        Ok(overlay)
+    }
+
+    /// Offset of block `lba` in the sidecar
+    fn data_offset(&self, lba: u32) -> u64 {
+        (BLOCK_SIZE + self.bitmap.len()) as u64 + lba as u64 * BLOCK_SIZE as u64
+    }
+
+    /// True if block `lba` has been written to the overlay.
+    pub fn contains(&self, lba: u32) -> bool {
+        self.bitmap[lba as usize / 8] & (0x80 >> (lba % 8)) != 0
+    }
+
+    /// The number of blocks held in the overlay.
+    pub fn count(&self) -> u32 {
+        self.bitmap.iter().map(|b| b.count_ones()).sum()
+    }
+
+    pub fn read(&mut self, lba: u32, buf: &mut [u8]) -> io::Result<()> {
+        let offset = self.data_offset(lba);
+        match &mut self.file {
+            Some(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(buf)
+            }
+            None => {
+                buf.copy_from_slice(&self.memory[&lba]);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write(&mut self, lba: u32, data: &[u8]) -> io::Result<()> {
+        let index = lba as usize / 8;
+        self.bitmap[index] |= 0x80 >> (lba % 8);
+
+        let offset = self.data_offset(lba);
+        match &mut self.file {
+            Some(file) => {
+                // Data first, so the bitmap never claims a block that
+                // was not written.
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)?;
+                file.seek(SeekFrom::Start((BLOCK_SIZE + index) as u64))?;
+                file.write_all(&self.bitmap[index..=index])
+            }
+            None => {
+                self.memory.insert(lba, data.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy every block in the overlay into `base`, then empty the
+    /// overlay. Returns the number of blocks copied.
+    pub fn commit(&mut self, base: &mut File) -> io::Result<u32> {
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut count = 0;
+
+        let present: Vec<u32> = (0..self.blocks).filter(|lba| self.contains(*lba)).collect();
+        for lba in present {
+            self.read(lba, &mut buf)?;
+            base.seek(SeekFrom::Start(lba as u64 * BLOCK_SIZE as u64))?;
+            base.write_all(&buf)?;
+            count += 1;
+        }
+        base.sync_all()?;
+
+        self.bitmap.fill(0);
+        self.memory.clear();
+        let end = self.data_offset(self.blocks);
+        let start = self.data_offset(0);
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(BLOCK_SIZE as u64))?;
+            file.write_all(&self.bitmap)?;
+            // Truncating and re-extending releases the data blocks
+            // on the host.
+            file.set_len(start)?;
+            file.set_len(end)?;
+        }
+
+        Ok(count)
+    }
+}
+
+fn bitmap_len(blocks: u32) -> usize {
+    (blocks as usize).div_ceil(8).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+/// Commit the sidecar overlay at `overlay` into the base image at
+/// `image`. Returns the number of blocks copied.
+pub fn commit(image: &str, overlay: &str) -> Result<u32, SimError> {
+    let err = |e: io::Error| SimError::Io(format!("{image}: {e}"));
+    let mut base = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .map_err(err)?;
+    let blocks = (base.metadata().map_err(err)?.len() / BLOCK_SIZE as u64) as u32;
+
+    if !std::path::Path::new(overlay).exists() {
+        return Err(SimError::Io(format!("{overlay}: no such overlay")));
+    }
+
+    let mut overlay = Overlay::open(&OverlayMode::Sidecar(overlay.to_string()), blocks)?;
+    overlay.commit(&mut base).map_err(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecar_persists() {
+        let path = std::env::temp_dir().join("tek4404-persist.cow");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mode = OverlayMode::Sidecar(path.to_string());
+
+        let mut overlay = Overlay::open(&mode, 100).unwrap();
+        overlay.write(42, &[0x5a; BLOCK_SIZE]).unwrap();
+        drop(overlay);
+
+        let mut overlay = Overlay::open(&mode, 100).unwrap();
+        assert!(overlay.contains(42));
+        assert!(!overlay.contains(43));
+        assert_eq!(1, overlay.count());
+        let mut buf = [0; BLOCK_SIZE];
+        overlay.read(42, &mut buf).unwrap();
+        assert_eq!([0x5a; BLOCK_SIZE], buf);
+
+        assert!(Overlay::open(&mode, 200).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_commit() {
+        let dir = std::env::temp_dir();
+        let image = dir.join("tek4404-commit.img");
+        let image = image.to_str().unwrap();
+        let sidecar = dir.join("tek4404-commit.cow");
+        let sidecar = sidecar.to_str().unwrap();
+        let _ = std::fs::remove_file(sidecar);
+        std::fs::write(image, vec![0; 8 * BLOCK_SIZE]).unwrap();
+
+        let mode = OverlayMode::Sidecar(sidecar.to_string());
+        let mut overlay = Overlay::open(&mode, 8).unwrap();
+        overlay.write(3, &[0xff; BLOCK_SIZE]).unwrap();
+        drop(overlay);
+
+        assert_eq!(Ok(1), commit(image, sidecar));
+        let data = std::fs::read(image).unwrap();
+        assert_eq!(vec![0xff; BLOCK_SIZE], data[3 * BLOCK_SIZE..4 * BLOCK_SIZE]);
+        assert_eq!(vec![0; 3 * BLOCK_SIZE], data[..3 * BLOCK_SIZE]);
+        assert_eq!(0, Overlay::open(&mode, 8).unwrap().count());
+
+        let _ = std::fs::remove_file(image);
+        let _ = std::fs::remove_file(sidecar);
+    }
+}
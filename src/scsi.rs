@@ -23,38 +23,52 @@
 // DEALINGS IN THE SOFTWARE.
 //
 use crate::bus::*;
-use crate::cpu::set_irq;
-use crate::err::BusError;
+use crate::disk::Disk;
+use crate::dma::Dma;
+use crate::err::{BusError, SimError};
+use crate::fault::{Fault, FaultRule, Faults};
+use crate::irq::{self, Interrupt};
+use crate::overlay::OverlayMode;
 use crate::service::ServiceKey;
+use crate::tape::Tape;
+use crate::trace::{Trace, Transaction};
 
-use log::info;
+use log::{debug, info, trace};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use tokio::time::Duration;
+use std::str::FromStr;
+use std::time::Duration;
 
 const HOST_ID: u8 = 7;
 const DIAG_COMPLETE: u8 = 0x80;
-const SCSI_INT: u8 = 3;
+
+/// The number of SCSI IDs on the bus, including the host
+pub const MAX_TARGETS: usize = 8;
 
 const CMD_SIZE: usize = 12;
 
 const ID_VALID: u8 = 0b10000000;
 
+const AUX_DRF: u8 = 0b10000000;
 const AUX_CZ: u8 = 0b00000010;
-#[allow(dead_code)]
+const AUX_PAUSED: u8 = 0b00000001;
 const AUX_IO: u8 = 0b00001000;
 const AUX_CD: u8 = 0b00010000;
-#[allow(dead_code)]
 const AUX_MSG: u8 = 0b00100000;
 
-#[allow(dead_code)]
 const INT_FC: u8 = 0b00000001;
-#[allow(dead_code)]
 const INT_BUS_SVC: u8 = 0b00000010;
-#[allow(dead_code)]
 const INT_DIS: u8 = 0b00000100;
 #[allow(dead_code)]
 const INT_SELECTED: u8 = 0b00001000;
+#[allow(dead_code)]
+const INT_RESELECTED: u8 = 0b00010000;
+const INT_INVALID: u8 = 0b01000000;
+
+// DMA Mode: data moves through the DMA controller, not the CPU
+const CMD_DMA: u8 = 0b10000000;
+// Single Byte Transfer: move one byte, ignoring the transfer counter
+const CMD_SBT: u8 = 0b01000000;
 
 //
 // MSG  C/D  I/O
@@ -67,22 +81,74 @@ const INT_SELECTED: u8 = 0b00001000;
 //  1    0    1      [unused]
 //  1    1    0      Message Out
 //  1    1    1      Message In
-#[allow(dead_code)]
-const PHASE_DATO: u8 = 0b00000000;
-#[allow(dead_code)]
-const PHASE_DATI: u8 = 0b00001000;
-const PHASE_CMND: u8 = 0b00010000;
-#[allow(dead_code)]
-const PHASE_STAT: u8 = 0b00011000;
-#[allow(dead_code)]
-const PHASE_MSGO: u8 = 0b00110000;
-#[allow(dead_code)]
-const PHASE_MSGI: u8 = 0b00111000;
+const PHASE_DATO: u8 = 0;
+const PHASE_DATI: u8 = AUX_IO;
+const PHASE_CMND: u8 = AUX_CD;
+const PHASE_STAT: u8 = AUX_CD | AUX_IO;
+const PHASE_MSGO: u8 = AUX_MSG | AUX_CD;
+const PHASE_MSGI: u8 = AUX_MSG | AUX_CD | AUX_IO;
+
+/// Time taken by a target to respond to selection
+const SELECT_DELAY: Duration = Duration::from_micros(20);
+/// The selection timeout counts down the upper 16 bits of the
+/// transfer counter once every 256 cycles of the 10 MHz chip clock.
+const SELECT_TIMEOUT_TICK: Duration = Duration::from_nanos(25_600);
+/// Time taken by a target to act on a command
+const COMMAND_DELAY: Duration = Duration::from_micros(100);
+/// Time taken to move one byte by DMA
+const DMA_BYTE_DELAY: Duration = Duration::from_nanos(500);
+
+//
+// Status and Message bytes
+//
+pub const STATUS_GOOD: u8 = 0x00;
+pub const STATUS_CHECK: u8 = 0x02;
+pub const STATUS_BUSY: u8 = 0x08;
+const MSG_COMMAND_COMPLETE: u8 = 0x00;
+const MSG_ABORT: u8 = 0x06;
+const MSG_BUS_DEVICE_RESET: u8 = 0x0c;
+
+//
+// Sense Keys
+//
+pub const SENSE_NONE: u8 = 0x0;
+pub const SENSE_MEDIUM_ERROR: u8 = 0x3;
+pub const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+pub const SENSE_UNIT_ATTENTION: u8 = 0x6;
+pub const SENSE_DATA_PROTECT: u8 = 0x7;
+pub const SENSE_BLANK_CHECK: u8 = 0x8;
+
+//
+// Sense Flags
+//
+pub const SENSE_FILEMARK: u8 = 0x80;
+pub const SENSE_EOM: u8 = 0x40;
+pub const SENSE_ILI: u8 = 0x20;
+
+//
+// Additional Sense Codes and Qualifiers
+//
+pub const ASC_NONE: u8 = 0x00;
+pub const ASCQ_FILEMARK: u8 = 0x01;
+pub const ASCQ_END_OF_MEDIUM: u8 = 0x02;
+pub const ASCQ_BEGINNING_OF_MEDIUM: u8 = 0x04;
+pub const ASCQ_END_OF_DATA: u8 = 0x05;
+pub const ASC_WRITE_FAULT: u8 = 0x03;
+pub const ASC_UNRECOVERED_READ: u8 = 0x11;
+pub const ASC_INVALID_OPCODE: u8 = 0x20;
+pub const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+pub const ASC_INVALID_FIELD: u8 = 0x24;
+pub const ASC_WRITE_PROTECTED: u8 = 0x27;
+pub const ASC_POWER_ON_RESET: u8 = 0x29;
+
+const SENSE_LEN: usize = 18;
 
 /// Register I/O Addresses
 #[derive(FromPrimitive)]
 enum RegAddr {
     Address = 0x7bc000,
+    AddressLow = 0x7bc002,
+    DmaControl = 0x7bc004,
     Data1 = 0x7be000,
     Command = 0x7be002,
     Control = 0x7be004,
@@ -91,34 +157,49 @@ enum RegAddr {
     Id = 0x7be00a,
     Interrupt = 0x7be00c,
     SourceId = 0x7be00e,
-    Data2 = 0x7be0010,
+    Data2 = 0x7be010,
     DiagStatus = 0x7be012,
     Xfer2 = 0x7be018,
     Xfer1 = 0x7be01a,
     Xfer0 = 0x7be01c,
 }
 
-/// SCSI Bus Phase
-#[derive(FromPrimitive)]
-enum Phase {
-    DataOut = 0,
-    DataIn = 1,
-    Command = 2,
-    Status = 3,
-    MessageOut = 6,
-    MessageIn = 8,
-}
-
 /// Controller State
-#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum State {
     BusFree,
-    // Arbitration is implied in BusFree -> Selected
+    // Arbitration is implied in BusFree -> Selecting
+    Selecting,
+    Reselecting,
     Selected,
+    MessageOut,
     Command,
-    Data,
-    Message,
+    // The target is acting on a command
+    Executing,
+    DataIn,
+    DataOut,
+    // The target is acting on data sent by the initiator
+    Writing,
     Status,
+    MessageIn,
+    // The last message byte has been received, and ACK is held until
+    // the initiator issues Message Accepted.
+    MessageHeld,
+}
+
+impl State {
+    /// The MSG, C/D and I/O bits driven by the target in this state.
+    fn phase(&self) -> u8 {
+        match self {
+            State::MessageOut => PHASE_MSGO,
+            State::Command | State::Executing => PHASE_CMND,
+            State::DataIn => PHASE_DATI,
+            State::DataOut | State::Writing => PHASE_DATO,
+            State::Status => PHASE_STAT,
+            State::MessageIn | State::MessageHeld => PHASE_MSGI,
+            _ => 0,
+        }
+    }
 }
 
 /// SCSI BUS Commands
@@ -152,7 +233,7 @@ enum Command {
 /// SCSI Opcodes
 #[allow(dead_code)]
 #[derive(FromPrimitive)]
-enum Op {
+pub enum Op {
     TestReady = 0x00,
     Rewind = 0x01,
     RequestSense = 0x03,
@@ -178,15 +259,319 @@ enum Op {
     ModeSense10 = 0x5a,
 }
 
-#[allow(dead_code)]
+impl Op {
+    /// The name of the command, as written in the SCSI standard
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::TestReady => "TEST UNIT READY",
+            Op::Rewind => "REWIND",
+            Op::RequestSense => "REQUEST SENSE",
+            Op::ReadBlockLimits => "READ BLOCK LIMITS",
+            Op::Read6 => "READ(6)",
+            Op::Write6 => "WRITE(6)",
+            Op::WriteFileMark => "WRITE FILEMARKS",
+            Op::Space => "SPACE",
+            Op::Inquiry => "INQUIRY",
+            Op::ModeSelect6 => "MODE SELECT(6)",
+            Op::Reserve => "RESERVE",
+            Op::Release => "RELEASE",
+            Op::Erase => "ERASE",
+            Op::ModeSense6 => "MODE SENSE(6)",
+            Op::StartStop => "START STOP UNIT",
+            Op::SendDiag => "SEND DIAGNOSTIC",
+            Op::PreventAllow => "PREVENT ALLOW",
+            Op::ReadCapacity => "READ CAPACITY",
+            Op::Read10 => "READ(10)",
+            Op::Write10 => "WRITE(10)",
+            Op::ReadLong => "READ LONG",
+            Op::ModeSelect10 => "MODE SELECT(10)",
+            Op::ModeSense10 => "MODE SENSE(10)",
+        }
+    }
+}
+
+/// The length of a command descriptor block, from its group code
+fn cdb_len(opcode: u8) -> usize {
+    match opcode >> 5 {
+        1 | 2 => 10,
+        5 => 12,
+        _ => 6,
+    }
+}
+
+/// Sense data reported by a target after CHECK CONDITION status
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+    /// Filemark, End of Medium, and Incorrect Length flags
+    pub flags: u8,
+    /// Command-specific information, such as a residual count
+    pub info: Option<u32>,
+}
+
+impl Sense {
+    pub fn new(key: u8, asc: u8) -> Self {
+        Sense {
+            key,
+            asc,
+            ..Default::default()
+        }
+    }
+
+    /// Extended sense data, as returned by REQUEST SENSE
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = vec![0; SENSE_LEN];
+        buf[0] = 0x70;
+        buf[2] = self.flags | self.key;
+        if let Some(info) = self.info {
+            buf[0] |= 0x80;
+            buf[3..7].copy_from_slice(&info.to_be_bytes());
+        }
+        buf[7] = (SENSE_LEN - 8) as u8;
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        buf
+    }
+}
+
+/// The data phase requested by a target in response to a command
+#[derive(Debug, PartialEq, Eq)]
+pub enum Transfer {
+    /// No data phase
+    None,
+    /// Data to be sent to the initiator
+    In(Vec<u8>),
+    /// The number of bytes expected from the initiator
+    Out(usize),
+}
+
+/// A device that may be attached to the SCSI bus
+pub trait ScsiTarget {
+    /// Act on the command descriptor block `cdb`. REQUEST SENSE is
+    /// handled by the controller and is never passed to the target.
+    fn command(&mut self, cdb: &[u8]) -> Result<Transfer, Sense>;
+
+    /// Accept the data sent by the initiator for the command `cdb`,
+    /// which previously returned `Transfer::Out`.
+    fn data_out(&mut self, _cdb: &[u8], _data: &[u8]) -> Result<(), Sense> {
+        Ok(())
+    }
+
+    /// Sense data for a command that moved data but still ended in
+    /// CHECK CONDITION, such as a tape read that stopped at a
+    /// filemark. Called after every successful command and data out.
+    fn check(&mut self) -> Option<Sense> {
+        None
+    }
+
+    /// True for sequential access devices, whose READ and WRITE
+    /// commands carry a length rather than a block address.
+    fn sequential(&self) -> bool {
+        false
+    }
+}
+
+pub type TargetDevice = Box<dyn ScsiTarget + Send + Sync>;
+
+/// The kinds of target that may be attached from the command line
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TargetKind {
+    Disk,
+    Tape,
+}
+
+/// A target to attach to the bus, given on the command line as
+/// `ID:TYPE:PATH`, for example `0:disk:system.img`. Options follow
+/// the type, separated by commas: `ro` write-protects the target,
+/// `overlay` or `overlay=FILE` puts a disk behind a copy-on-write
+/// overlay held in memory or in a sidecar file, and `create` creates
+/// a blank tape if there is no image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetConfig {
+    pub id: usize,
+    pub kind: TargetKind,
+    pub read_only: bool,
+    pub overlay: Option<OverlayMode>,
+    pub create: bool,
+    pub path: String,
+}
+
+impl TargetConfig {
+    pub fn open(&self) -> Result<TargetDevice, SimError> {
+        match self.kind {
+            TargetKind::Disk => Ok(Box::new(Disk::open(
+                &self.path,
+                self.read_only,
+                self.overlay.as_ref(),
+            )?)),
+            TargetKind::Tape => Ok(Box::new(Tape::open(
+                &self.path,
+                self.read_only,
+                self.create,
+            )?)),
+        }
+    }
+}
+
+impl FromStr for TargetConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(3, ':');
+
+        let (id, kind, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(kind), Some(path)) if !path.is_empty() => (id, kind, path),
+            _ => return Err(format!("expected ID:TYPE:PATH, got '{s}'")),
+        };
+
+        let id = match id.parse::<usize>() {
+            Ok(id) if id < HOST_ID as usize => id,
+            _ => return Err(format!("SCSI ID must be 0-{}, got '{id}'", HOST_ID - 1)),
+        };
+
+        let mut options = kind.split(',');
+        let kind = options.next().unwrap_or_default();
+        let mut read_only = false;
+        let mut overlay = None;
+        let mut create = false;
+
+        for opt in options {
+            match opt.split_once('=') {
+                None if opt == "ro" => read_only = true,
+                None if opt == "overlay" => overlay = Some(OverlayMode::Memory),
+                None if opt == "create" => create = true,
+                Some(("overlay", file)) if !file.is_empty() => {
+                    overlay = Some(OverlayMode::Sidecar(file.to_string()))
+                }
+                _ => return Err(format!("unknown SCSI target option '{opt}'")),
+            }
+        }
+
+        let kind = match kind {
+            "disk" | "hd" => TargetKind::Disk,
+            "tape" => TargetKind::Tape,
+            _ => return Err(format!("unknown SCSI target type '{kind}'")),
+        };
+
+        if overlay.is_some() && kind != TargetKind::Disk {
+            return Err(String::from("only disks can have an overlay"));
+        }
+        if create && kind != TargetKind::Tape {
+            return Err(String::from("only tapes can be created when attached"));
+        }
+
+        Ok(TargetConfig {
+            id,
+            kind,
+            read_only,
+            overlay,
+            create,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// A target attached to the bus, along with its pending sense data
+/// and any faults injected into it.
+struct Unit {
+    target: TargetDevice,
+    sense: Sense,
+    faults: Faults,
+    /// A UNIT ATTENTION condition is pending
+    attention: bool,
+}
+
+impl Unit {
+    fn new(target: TargetDevice) -> Self {
+        Unit {
+            target,
+            sense: Sense::default(),
+            faults: Faults::default(),
+            attention: false,
+        }
+    }
+
+    fn status(&mut self, result: Result<(), Sense>) -> u8 {
+        match result {
+            Ok(()) => {
+                self.sense = Sense::default();
+                STATUS_GOOD
+            }
+            Err(sense) => {
+                debug!("CHECK CONDITION: {:?}", sense);
+                self.sense = sense;
+                STATUS_CHECK
+            }
+        }
+    }
+
+    fn command(&mut self, cdb: &[u8]) -> (u8, Transfer) {
+        if self.faults.busy() {
+            debug!("BUSY (injected)");
+            return (STATUS_BUSY, Transfer::None);
+        }
+
+        // INQUIRY and REQUEST SENSE are answered in spite of a pending
+        // UNIT ATTENTION; anything else reports it.
+        if self.attention && cdb[0] != Op::Inquiry as u8 && cdb[0] != Op::RequestSense as u8 {
+            self.attention = false;
+            let sense = Sense::new(SENSE_UNIT_ATTENTION, ASC_POWER_ON_RESET);
+            return (self.status(Err(sense)), Transfer::None);
+        }
+
+        // A tape's READ and WRITE carry a length, not a block address.
+        let medium_error = if self.target.sequential() {
+            None
+        } else {
+            self.faults.medium_error(cdb)
+        };
+        if let Some(sense) = medium_error {
+            debug!("MEDIUM ERROR (injected)");
+            return (self.status(Err(sense)), Transfer::None);
+        }
+
+        if cdb[0] == Op::RequestSense as u8 {
+            let mut data = self.sense.to_bytes();
+            // An allocation length of zero requests four bytes of
+            // non-extended sense.
+            data.truncate(match cdb[4] {
+                0 => 4,
+                n => n as usize,
+            });
+            self.sense = Sense::default();
+            return (STATUS_GOOD, Transfer::In(data));
+        }
+
+        match self.target.command(cdb) {
+            Ok(transfer) => {
+                let result = self.checked();
+                (self.status(result), transfer)
+            }
+            Err(sense) => (self.status(Err(sense)), Transfer::None),
+        }
+    }
+
+    fn data_out(&mut self, cdb: &[u8], data: &[u8]) -> u8 {
+        let result = self.target.data_out(cdb, data).and_then(|_| self.checked());
+        self.status(result)
+    }
+
+    fn checked(&mut self) -> Result<(), Sense> {
+        match self.target.check() {
+            Some(sense) => Err(sense),
+            None => Ok(()),
+        }
+    }
+}
+
 pub struct Scsi {
     state: State,
-    address: usize,
     data1: u8,
     command: u8,
     control: u8,
     dest_id: u8,
-    aux_stat: u8,
     id: u8,
     interrupt: u8,
     source_id: u8,
@@ -195,18 +580,33 @@ pub struct Scsi {
     xfer: u32,
     cmd: [u8; CMD_SIZE],
     cmd_ptr: usize,
+    units: [Option<Unit>; MAX_TARGETS],
+    selected: Option<usize>,
+    atn: bool,
+    resume: State,
+    transferring: bool,
+    single: bool,
+    dma_mode: bool,
+    paused: bool,
+    disabled: bool,
+    drf: bool,
+    status: u8,
+    buf: Vec<u8>,
+    buf_ptr: usize,
+    out_len: usize,
+    dma: Dma,
+    trace: Option<Trace>,
+    txn: Option<Transaction>,
 }
 
 impl Scsi {
     pub fn new() -> Self {
         Scsi {
             state: State::BusFree,
-            address: 0,
             data1: 0,
             command: 0,
             control: 0,
             dest_id: 0,
-            aux_stat: 0,
             id: HOST_ID,
             interrupt: 0,
             source_id: 0,
@@ -215,74 +615,625 @@ impl Scsi {
             xfer: 0,
             cmd: [0; CMD_SIZE],
             cmd_ptr: 0,
+            units: Default::default(),
+            selected: None,
+            atn: false,
+            resume: State::Command,
+            transferring: false,
+            single: false,
+            dma_mode: false,
+            paused: false,
+            disabled: false,
+            drf: false,
+            status: STATUS_GOOD,
+            buf: Vec::new(),
+            buf_ptr: 0,
+            out_len: 0,
+            dma: Dma::new(),
+            trace: None,
+            txn: None,
+        }
+    }
+
+    /// Attach a target to the bus at SCSI ID `id`.
+    pub fn attach(&mut self, id: usize, target: TargetDevice) -> Result<(), SimError> {
+        if id >= HOST_ID as usize {
+            return Err(SimError::Init(format!("SCSI ID {id} is reserved")));
+        }
+        if self.units[id].is_some() {
+            return Err(SimError::Init(format!("SCSI ID {id} is already in use")));
+        }
+
+        info!("Attaching SCSI target at ID {}", id);
+        self.units[id] = Some(Unit::new(target));
+        Ok(())
+    }
+
+    /// Inject a fault into an attached target.
+    pub fn add_fault(&mut self, rule: FaultRule) -> Result<(), SimError> {
+        match self.units.get_mut(rule.id).and_then(|u| u.as_mut()) {
+            // Medium errors are by block address, which a tape does
+            // not have.
+            Some(unit)
+                if unit.target.sequential() && matches!(rule.fault, Fault::MediumError { .. }) =>
+            {
+                Err(SimError::Init(format!(
+                    "the target at SCSI ID {} is a tape, and can't have medium errors",
+                    rule.id
+                )))
+            }
+            Some(unit) => {
+                info!("SCSI ID {}: injecting {:?}", rule.id, rule.fault);
+                unit.faults.add(rule);
+                // Faults are in place from power on.
+                unit.attention = unit.faults.unit_attention();
+                Ok(())
+            }
+            None => Err(SimError::Init(format!(
+                "no SCSI target at ID {} for fault injection",
+                rule.id
+            ))),
+        }
+    }
+
+    /// Record every transaction on the bus in `trace`.
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    /// Write the transaction in progress to the trace, if there is one.
+    fn end_transaction(&mut self, outcome: Option<&str>) {
+        if let (Some(trace), Some(txn)) = (&mut self.trace, self.txn.take()) {
+            trace.record(&txn, outcome);
+        }
+    }
+
+    /// Note the status of the command in progress, and its sense data
+    /// if it failed.
+    fn trace_status(&mut self) {
+        let sense = self
+            .selected
+            .and_then(|id| self.units[id].as_ref())
+            .map(|unit| unit.sense);
+        if let Some(txn) = &mut self.txn {
+            txn.status = Some(self.status);
+            txn.sense = match self.status {
+                STATUS_CHECK => sense,
+                _ => None,
+            };
+        }
+    }
+
+    /// Post an interrupt condition to the CPU.
+    fn raise(&mut self, irq: u8) {
+        self.interrupt |= irq;
+        self.update_irq();
+    }
+
+    /// Drive the SCSI and DMA interrupt lines.
+    fn update_irq(&self) {
+        irq::set(Interrupt::Scsi, self.interrupt != 0);
+        irq::set(Interrupt::Dma, self.dma.interrupt());
+    }
+
+    fn aux_status(&self) -> u8 {
+        let mut aux = self.state.phase();
+        if self.drf {
+            aux |= AUX_DRF;
+        }
+        if self.paused {
+            aux |= AUX_PAUSED;
+        }
+        if self.xfer == 0 {
+            aux |= AUX_CZ;
         }
+        aux
     }
 
     /// Controller Reset
     fn reset(&mut self) {
-        info!("RESET");
+        debug!("RESET");
+        if self.connected() {
+            self.end_transaction(Some("RESET"));
+        }
 
+        self.state = State::BusFree;
         self.data1 = 0;
         self.control = 0;
         self.dest_id = 0;
-        self.aux_stat = AUX_CZ;
         self.interrupt = 0;
         self.source_id = 0;
         self.data2 = 0;
         self.diag_status = DIAG_COMPLETE;
         self.xfer = 0;
         self.cmd_ptr = 0;
+        self.selected = None;
+        self.atn = false;
+        self.transferring = false;
+        self.paused = false;
+        self.disabled = false;
+        self.drf = false;
+        // Whatever the chip was waiting on is abandoned.
+        cancel!(ServiceKey::Scsi);
+
+        for unit in self.units.iter_mut().flatten() {
+            unit.attention |= unit.faults.unit_attention();
+        }
+    }
+
+    /// True if the chip is connected to a target as an initiator.
+    fn connected(&self) -> bool {
+        !matches!(
+            self.state,
+            State::BusFree | State::Selecting | State::Reselecting
+        )
+    }
+
+    /// Release the bus, abandoning any selection or transfer.
+    fn bus_free(&mut self) {
+        self.state = State::BusFree;
+        self.selected = None;
+        self.atn = false;
+        self.transferring = false;
+        self.paused = false;
+        self.drf = false;
+    }
+
+    /// A command issued in the wrong state is rejected with an
+    /// Invalid Command interrupt.
+    fn invalid(&mut self, c: u8) {
+        info!("Invalid scsi command: 0x{:02x} (b{:06b})", c, c);
+        self.raise(INT_INVALID);
+    }
+
+    /// Disconnect drops off the bus immediately. No interrupt is
+    /// generated.
+    fn disconnect(&mut self) {
+        debug!("DISCONNECT");
+        self.end_transaction(Some("DISCONNECTED"));
+        self.bus_free();
+    }
+
+    /// Pause suspends a Transfer Info in progress, which resumes when
+    /// Transfer Info is issued again.
+    fn pause(&mut self) {
+        debug!("PAUSE (transferring={})", self.transferring);
+        if self.transferring {
+            self.paused = true;
+        }
+    }
+
+    /// Assert ATN. The target will enter Message Out before the next
+    /// information phase it requests.
+    fn set_atn(&mut self, c: u8) {
+        if self.connected() || self.state == State::Selecting {
+            debug!("SET ATN");
+            self.atn = true;
+        } else {
+            self.invalid(c);
+        }
+    }
+
+    /// Chip Disable stops the chip from acting on anything but Chip
+    /// Reset.
+    fn chip_disable(&mut self) {
+        debug!("CHIP DISABLE");
+        self.bus_free();
+        self.disabled = true;
+    }
+
+    /// Reselect an initiator, as a target would after disconnecting.
+    /// No other initiator shares the 4404's bus, so reselection always
+    /// times out.
+    fn reselect(&mut self, c: u8) {
+        if self.state != State::BusFree {
+            return self.invalid(c);
+        }
+
+        debug!("RESELECT (id={}, timeout={})", self.dest_id & 7, self.xfer);
+        self.state = State::Reselecting;
+        schedule!(ServiceKey::Scsi, self.select_timeout());
+    }
+
+    /// Run the chip's internal self-diagnostic, which always passes.
+    fn diagnostic(&mut self, c: u8) {
+        if self.state != State::BusFree {
+            return self.invalid(c);
+        }
+
+        debug!("DIAGNOSTIC");
+        self.diag_status = DIAG_COMPLETE;
+        self.raise(INT_FC);
+    }
+
+    /// A target that sees ATN asserted enters Message Out in place of
+    /// the next information phase it would have requested.
+    fn attention(&mut self, state: State) -> State {
+        let info_phase = matches!(
+            state,
+            State::Command | State::DataIn | State::DataOut | State::Status | State::MessageIn
+        );
+
+        if self.atn && info_phase {
+            self.resume = state;
+            State::MessageOut
+        } else {
+            state
+        }
     }
 
     /// Select (with or without attention)
     // - Causes interrupt.
-    // - Success: INT_REGISTER = 0b00000001, AUX_STATUS = C/D
-    // - Failure: INT_REGISTER = 0b01000000, AUX_STATUS = C/D (I think?)
-    fn select(&mut self, atn: bool) {
-        info!("SELECT (atn={}, timeout={})", atn, self.xfer);
+    // - Success: Function Complete, followed by Bus Service when the
+    //   target requests the first information phase.
+    // - Failure: Disconnected, once the selection times out.
+    fn select(&mut self, c: u8, atn: bool) {
+        if self.state != State::BusFree {
+            return self.invalid(c);
+        }
+
+        let id = (self.dest_id & 7) as usize;
+        debug!("SELECT (atn={}, id={}, timeout={})", atn, id, self.xfer);
+        if self.trace.is_some() {
+            self.txn = Some(Transaction::new(id));
+        }
 
-        self.state = State::Selected;
+        self.state = State::Selecting;
+        self.atn = atn;
+        self.resume = State::Command;
+        self.selected = match self.units.get_mut(id).and_then(|u| u.as_mut()) {
+            Some(unit) => {
+                if unit.faults.selection_timeout() {
+                    debug!("SELECTION TIMEOUT (injected)");
+                    None
+                } else {
+                    Some(id)
+                }
+            }
+            None => None,
+        };
 
-        self.aux_stat = AUX_CD;
-        self.interrupt = INT_FC;
-        self.source_id = ID_VALID | self.dest_id;
+        // Nobody answers at an empty ID, so the chip waits out the
+        // full timeout before giving up.
+        let delay = match self.selected {
+            Some(_) => SELECT_DELAY,
+            None => self.select_timeout(),
+        };
+        schedule!(ServiceKey::Scsi, delay);
+    }
 
-        schedule!(ServiceKey::Scsi, Duration::from_millis(750));
+    /// The time the chip will wait for a response to selection or
+    /// reselection. A count of zero waits for the longest possible
+    /// time, as the counter wraps before it expires.
+    fn select_timeout(&self) -> Duration {
+        let count = match self.xfer >> 8 {
+            0 => 0x10000,
+            n => n,
+        };
+        SELECT_TIMEOUT_TICK * count
     }
 
-    fn transfer_info(&mut self) {
-        info!(
+    fn transfer_info(&mut self, c: u8) {
+        if !self.connected() {
+            return self.invalid(c);
+        }
+
+        debug!(
             "(COMMAND) Transfer Info. XFER={} ({:x})",
             self.xfer, self.xfer
         );
-        self.cmd_ptr = 0;
+        self.transferring = true;
+        self.paused = false;
+
+        if self.dma_mode {
+            self.dma.start();
+            schedule!(ServiceKey::Scsi, DMA_BYTE_DELAY * self.xfer.max(1));
+        } else {
+            self.load_data();
+        }
+    }
+
+    /// Move data between the target and memory until the transfer
+    /// counter runs out or the target changes phase.
+    fn run_dma(&mut self, bus: &mut Bus) {
+        let mut failed = false;
+
+        while self.transferring && !self.paused {
+            let result = match self.state {
+                State::DataIn | State::Status | State::MessageIn => {
+                    self.load_data();
+                    self.drf = false;
+                    self.dma.write(bus, self.data1).map(|_| self.input_done())
+                }
+                State::MessageOut | State::Command | State::DataOut => {
+                    self.dma.read(bus).map(|value| self.output(value))
+                }
+                _ => break,
+            };
 
-        schedule!(ServiceKey::Scsi, Duration::from_millis(750));
+            if result.is_err() {
+                failed = true;
+                self.transferring = false;
+                self.raise(INT_FC);
+            }
+        }
+
+        // A paused transfer carries on when Transfer Info is reissued.
+        if !self.paused {
+            self.dma.finish(failed);
+            self.update_irq();
+        }
     }
 
-    fn transfer_pad(&mut self) {
-        info!(
+    /// Transfer Pad moves the requested number of bytes without the
+    /// CPU's involvement, sending zeros or discarding what is received.
+    fn transfer_pad(&mut self, c: u8) {
+        if !self.connected() {
+            return self.invalid(c);
+        }
+
+        debug!(
             "(COMMAND) Transfer Pad. XFER={} ({:x})",
             self.xfer, self.xfer
         );
-        self.cmd_ptr = 0;
+        self.transferring = true;
+        self.paused = false;
+
+        while self.transferring {
+            match self.state {
+                State::DataIn | State::Status | State::MessageIn => {
+                    self.load_data();
+                    self.drf = false;
+                    self.input_done();
+                }
+                State::MessageOut | State::Command | State::DataOut => self.output(0),
+                _ => break,
+            }
+        }
+    }
+
+    /// Release ACK after Message In. The only message our targets
+    /// send is Command Complete, after which they leave the bus.
+    fn message_accepted(&mut self, c: u8) {
+        if self.state == State::MessageHeld {
+            debug!("[MESSAGE->BUSFREE]");
+            self.end_transaction(None);
+            self.bus_free();
+            self.raise(INT_DIS);
+        } else if !self.connected() {
+            self.invalid(c);
+        }
+    }
+
+    /// Enter a new information transfer phase at the target's request.
+    /// Any Transfer Info in progress is terminated by the phase change.
+    fn enter(&mut self, state: State) {
+        let state = self.attention(state);
+        debug!("[{:?}->{:?}]", self.state, state);
+        self.state = state;
+        self.transferring = false;
+        self.drf = false;
+        self.raise(INT_BUS_SVC);
+    }
+
+    /// Place the next byte from the target in the data register, if
+    /// the target is in an input phase.
+    fn load_data(&mut self) {
+        if !self.transferring {
+            return;
+        }
+
+        self.data1 = match self.state {
+            State::DataIn => self.buf[self.buf_ptr],
+            State::Status => self.status,
+            State::MessageIn => MSG_COMMAND_COMPLETE,
+            _ => return,
+        };
+        self.drf = true;
+    }
+
+    /// Count down one byte moved, unless this is a single byte transfer.
+    fn count(&mut self) {
+        if !self.single {
+            self.xfer = self.xfer.saturating_sub(1);
+        }
+    }
+
+    /// The initiator has taken a byte from the data register.
+    fn input_done(&mut self) {
+        self.count();
+
+        let next = match self.state {
+            State::DataIn => {
+                self.buf_ptr += 1;
+                if self.buf_ptr < self.buf.len() {
+                    None
+                } else {
+                    Some(State::Status)
+                }
+            }
+            State::Status => Some(State::MessageIn),
+            State::MessageIn => Some(State::MessageHeld),
+            _ => None,
+        };
 
-        schedule!(ServiceKey::Scsi, Duration::from_millis(75));
+        self.advance(next);
+    }
+
+    /// The initiator has sent a byte to the target.
+    fn output(&mut self, value: u8) {
+        self.count();
+
+        let next = match self.state {
+            State::MessageOut => {
+                debug!("MESSAGE OUT: {:02x}", value);
+                self.atn = false;
+                if value == MSG_ABORT || value == MSG_BUS_DEVICE_RESET {
+                    debug!("[MESSAGE->BUSFREE]");
+                    self.end_transaction(Some(match value {
+                        MSG_ABORT => "ABORTED",
+                        _ => "DEVICE RESET",
+                    }));
+                    self.bus_free();
+                    self.raise(INT_DIS);
+                    return;
+                }
+                Some(self.resume)
+            }
+            State::Command => {
+                debug!("CMD[{:02}] = {:02x}", self.cmd_ptr, value);
+                self.cmd[self.cmd_ptr] = value;
+                self.cmd_ptr += 1;
+                if self.cmd_ptr == cdb_len(self.cmd[0]) {
+                    let delay = COMMAND_DELAY + self.fault_delay();
+                    schedule!(ServiceKey::Scsi, delay);
+                    Some(State::Executing)
+                } else {
+                    None
+                }
+            }
+            State::DataOut => {
+                self.buf.push(value);
+                if self.buf.len() == self.out_len {
+                    schedule!(ServiceKey::Scsi, COMMAND_DELAY);
+                    Some(State::Writing)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        self.advance(next);
+    }
+
+    /// Continue or terminate Transfer Info after a byte has moved
+    /// between initiator and target.
+    fn advance(&mut self, next: Option<State>) {
+        if let Some(state) = next {
+            let state = self.attention(state);
+            debug!("[{:?}->{:?}]", self.state, state);
+            self.state = state;
+            if state == State::Command {
+                self.cmd_ptr = 0;
+            }
+        }
+
+        let requesting = matches!(
+            self.state,
+            State::MessageOut | State::Command | State::DataOut | State::Status | State::MessageIn
+        );
+
+        if self.xfer == 0 || self.single || self.state == State::MessageHeld {
+            self.transferring = false;
+            if next.is_some() && requesting {
+                self.raise(INT_FC | INT_BUS_SVC);
+            } else {
+                self.raise(INT_FC);
+            }
+        } else if next.is_some() && requesting {
+            self.transferring = false;
+            self.raise(INT_BUS_SVC);
+        } else {
+            self.load_data();
+        }
+    }
+
+    /// Extra time the selected target takes to act on a command
+    fn fault_delay(&mut self) -> Duration {
+        match self.selected.and_then(|id| self.units[id].as_mut()) {
+            Some(unit) => unit.faults.delay(),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Hand the completed command to the selected target.
+    fn execute(&mut self) {
+        let cdb = self.cmd;
+        let cdb = &cdb[..cdb_len(cdb[0])];
+
+        let unit = self.selected.and_then(|id| self.units[id].as_mut());
+        let sequential = unit.as_ref().is_some_and(|u| u.target.sequential());
+        let (status, transfer) = match unit {
+            Some(unit) => unit.command(cdb),
+            None => (STATUS_CHECK, Transfer::None),
+        };
+        self.status = status;
+
+        if let Some(txn) = &mut self.txn {
+            txn.cdb = cdb.to_vec();
+            txn.sequential = sequential;
+            match &transfer {
+                Transfer::In(data) => txn.data_in = data.len(),
+                Transfer::Out(len) => txn.data_out = *len,
+                Transfer::None => {}
+            }
+        }
+        self.trace_status();
+
+        match transfer {
+            Transfer::In(data) if !data.is_empty() => {
+                self.buf = data;
+                self.buf_ptr = 0;
+                self.enter(State::DataIn);
+            }
+            Transfer::Out(len) if len > 0 => {
+                self.buf.clear();
+                self.out_len = len;
+                self.enter(State::DataOut);
+            }
+            _ => self.enter(State::Status),
+        }
+    }
+
+    /// Hand the data received during Data Out to the selected target.
+    fn write_done(&mut self) {
+        let cdb = self.cmd;
+        let cdb = &cdb[..cdb_len(cdb[0])];
+
+        self.status = match self.selected.and_then(|id| self.units[id].as_mut()) {
+            Some(unit) => unit.data_out(cdb, &self.buf),
+            None => STATUS_CHECK,
+        };
+        self.trace_status();
+        self.enter(State::Status);
     }
 
     /// Process the last command.
     fn handle_command(&mut self) {
         let c = self.command & 0x1f;
+        self.single = self.command & CMD_SBT != 0;
+        self.dma_mode = self.command & CMD_DMA != 0;
+
+        if self.disabled && c != Command::ChipReset as u8 {
+            return self.invalid(c);
+        }
 
         match FromPrimitive::from_u8(c) {
             Some(Command::ChipReset) => self.reset(),
-            Some(Command::SelectWithAtn) => self.select(true),
-            Some(Command::SelectWithoutAtn) => self.select(false),
-            Some(Command::TransferInfo) => self.transfer_info(),
-            Some(Command::TransferPad) => self.transfer_pad(),
-            _ => {
-                info!("Unhandled scsi command: 0x{:02x} (b{:06b})", c, c);
-            }
+            Some(Command::Disconnect) => self.disconnect(),
+            Some(Command::Paused) => self.pause(),
+            Some(Command::SetAtn) => self.set_atn(c),
+            Some(Command::MessageAccepted) => self.message_accepted(c),
+            Some(Command::ChipDisable) => self.chip_disable(),
+            Some(Command::SelectWithAtn) => self.select(c, true),
+            Some(Command::SelectWithoutAtn) => self.select(c, false),
+            Some(Command::Reselect) => self.reselect(c),
+            Some(Command::Diagnostic) => self.diagnostic(c),
+            Some(Command::TransferInfo) => self.transfer_info(c),
+            Some(Command::TransferPad) => self.transfer_pad(c),
+            // The target role commands are only valid once the chip
+            // has been selected by another initiator, and the 4404 has
+            // no other initiator on its bus.
+            Some(Command::RxCmd)
+            | Some(Command::RxData)
+            | Some(Command::RxMessageOut)
+            | Some(Command::RxUnspInfoOut)
+            | Some(Command::TxStatus)
+            | Some(Command::TxData)
+            | Some(Command::TxMessageOut)
+            | Some(Command::TxUnspInfoIn)
+            | None => self.invalid(c),
         }
     }
 }
@@ -291,51 +1242,67 @@ impl IoDevice for Scsi {
     fn read_8(&mut self, _bus: &mut Bus, address: usize) -> Result<u8, BusError> {
         match FromPrimitive::from_usize(address) {
             Some(RegAddr::Data1) => {
-                info!("(READ) DATA1={:02x}", self.data1);
-                Ok(self.data1)
+                let val = self.data1;
+                trace!("(READ) DATA1={:02x}", val);
+                if self.transferring && !self.paused && self.drf {
+                    self.drf = false;
+                    self.input_done();
+                }
+                Ok(val)
             }
             Some(RegAddr::Command) => {
-                info!("(READ) COMMAND={:02x}", self.command);
+                trace!("(READ) COMMAND={:02x}", self.command);
                 Ok(self.command)
             }
             Some(RegAddr::Control) => {
-                info!("(READ) CONTROL={:02x}", self.control);
+                trace!("(READ) CONTROL={:02x}", self.control);
                 Ok(self.control)
             }
             Some(RegAddr::DestId) => {
-                info!("(READ) DEST_ID={}", self.dest_id);
+                trace!("(READ) DEST_ID={}", self.dest_id);
                 Ok(self.dest_id)
             }
             Some(RegAddr::AuxStatus) => {
-                info!("(READ) AUX_STAT: {:02x}", self.aux_stat);
-                Ok(self.aux_stat)
+                let aux = self.aux_status();
+                trace!("(READ) AUX_STAT: {:02x}", aux);
+                Ok(aux)
             }
             Some(RegAddr::Id) => {
-                info!("(READ) ID: {}", self.id);
+                trace!("(READ) ID: {}", self.id);
                 Ok(self.id)
             }
             Some(RegAddr::Interrupt) => {
+                // Reading the interrupt register clears it, and
+                // releases the interrupt request.
                 let irq = self.interrupt;
-                info!("(READ) INTERRUPT: ({:02x})", irq);
+                trace!("(READ) INTERRUPT: ({:02x})", irq);
+                self.interrupt = 0;
+                self.update_irq();
                 Ok(irq)
             }
             Some(RegAddr::SourceId) => {
-                info!("(READ) SOURCE_ID: {}", self.source_id);
+                trace!("(READ) SOURCE_ID: {}", self.source_id);
                 Ok(self.source_id)
             }
             Some(RegAddr::Data2) => {
-                info!("(READ) DATA2: {:02x}", self.data2);
+                trace!("(READ) DATA2: {:02x}", self.data2);
                 Ok(self.data2)
             }
             Some(RegAddr::DiagStatus) => {
-                info!("(READ) DIAG_STATUS: {:02x}", self.diag_status);
+                trace!("(READ) DIAG_STATUS: {:02x}", self.diag_status);
                 Ok(self.diag_status)
             }
             Some(RegAddr::Xfer2) => Ok((self.xfer >> 16) as u8),
             Some(RegAddr::Xfer1) => Ok((self.xfer >> 8) as u8),
             Some(RegAddr::Xfer0) => Ok(self.xfer as u8),
+            Some(RegAddr::DmaControl) => {
+                let status = self.dma.read_status();
+                trace!("(READ) DMA_STATUS: {:02x}", status);
+                self.update_irq();
+                Ok(status)
+            }
             _ => {
-                info!("READ: Unhandled.");
+                trace!("READ: Unhandled.");
                 Ok(0)
             }
         }
@@ -344,91 +1311,478 @@ impl IoDevice for Scsi {
     fn write_8(&mut self, _bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
         match FromPrimitive::from_usize(address) {
             Some(RegAddr::Address) => {
-                info!("(WRITE) ADDRESS = {:02x}", value);
+                trace!("(WRITE) ADDRESS = {:02x}", value);
+                self.dma.shift_address(value);
+            }
+            Some(RegAddr::DmaControl) => {
+                trace!("(WRITE) DMA_CONTROL = {:02x}", value);
+                self.dma.set_control(value);
+                self.update_irq();
             }
             Some(RegAddr::Data1) => {
-                info!("(WRITE)    CMD[{:02}] = {:02x}", self.cmd_ptr, value);
-                self.cmd[self.cmd_ptr] = value;
-                if self.cmd_ptr < CMD_SIZE {
-                    self.cmd_ptr += 1;
-                    if self.cmd_ptr == (self.xfer as usize) {
-                        info!(
-                            ">>> EXECUTING {} BYTE SCSI COMMAND: {:02x}",
-                            self.xfer, self.cmd[0]
-                        );
-                    }
+                trace!("(WRITE) DATA1 = {:02x}", value);
+                self.data1 = value;
+                if self.transferring && !self.paused {
+                    self.output(value);
                 }
             }
             Some(RegAddr::Command) => {
-                info!("(WRITE) COMMAND = {:02x}", value);
+                trace!("(WRITE) COMMAND = {:02x}", value);
                 self.command = value;
                 self.handle_command();
             }
             Some(RegAddr::Control) => {
-                info!("(WRITE) CONTROL = {:02x}", value);
+                trace!("(WRITE) CONTROL = {:02x}", value);
                 self.control = value;
             }
             Some(RegAddr::DestId) => {
-                info!("(WRITE) DEST_ID = {:02x}", value);
+                trace!("(WRITE) DEST_ID = {:02x}", value);
                 self.dest_id = value;
             }
             Some(RegAddr::Id) => {
-                info!("(WRITE) ID = {:02x}", value);
+                trace!("(WRITE) ID = {:02x}", value);
                 self.id = value;
             }
             Some(RegAddr::Xfer2) => {
-                info!("(WRITE) XFER2 = {:02x}", value);
+                trace!("(WRITE) XFER2 = {:02x}", value);
                 self.xfer &= !(0xff << 16);
                 self.xfer |= (value as u32) << 16;
             }
             Some(RegAddr::Xfer1) => {
-                info!("(WRITE) XFER1 = {:02x}", value);
+                trace!("(WRITE) XFER1 = {:02x}", value);
                 self.xfer &= !(0xff << 8);
                 self.xfer |= (value as u32) << 8;
             }
             Some(RegAddr::Xfer0) => {
-                info!("(WRITE) XFER0 = {:02x}", value);
+                trace!("(WRITE) XFER0 = {:02x}", value);
                 self.xfer &= !(0xff);
                 self.xfer |= value as u32;
             }
             _ => {
-                info!("(WRITE 8) addr={:08x} val={:02x}", address, value);
+                trace!("(WRITE 8) addr={:08x} val={:02x}", address, value);
             }
         }
 
         Ok(())
     }
 
-    fn service(&mut self) {
+    fn read_16(&mut self, bus: &mut Bus, address: usize) -> Result<u16, BusError> {
+        match FromPrimitive::from_usize(address) {
+            Some(RegAddr::Address) => Ok((self.dma.address() >> 16) as u16),
+            Some(RegAddr::AddressLow) => Ok(self.dma.address() as u16),
+            _ => self.read_8(bus, address).map(|b| b as u16),
+        }
+    }
+
+    fn read_32(&mut self, bus: &mut Bus, address: usize) -> Result<u32, BusError> {
+        match FromPrimitive::from_usize(address) {
+            Some(RegAddr::Address) => Ok(self.dma.address()),
+            _ => self.read_8(bus, address).map(|b| b as u32),
+        }
+    }
+
+    fn write_16(&mut self, bus: &mut Bus, address: usize, value: u16) -> Result<(), BusError> {
+        match FromPrimitive::from_usize(address) {
+            Some(RegAddr::Address) => {
+                let low = self.dma.address() & 0xffff;
+                self.dma.set_address(((value as u32) << 16) | low);
+                Ok(())
+            }
+            Some(RegAddr::AddressLow) => {
+                let high = self.dma.address() & 0xffff0000;
+                self.dma.set_address(high | value as u32);
+                Ok(())
+            }
+            _ => self.write_8(bus, address, value as u8),
+        }
+    }
+
+    fn write_32(&mut self, bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
+        match FromPrimitive::from_usize(address) {
+            Some(RegAddr::Address) => {
+                self.dma.set_address(value);
+                Ok(())
+            }
+            _ => self.write_8(bus, address, value as u8),
+        }
+    }
+
+    fn service(&mut self, bus: &mut Bus, _tag: u8) {
+        if self.transferring && self.dma.busy() {
+            return self.run_dma(bus);
+        }
+
         match self.state {
-            State::BusFree => {
-                info!("[BUSFREE->SELECTED]");
-                self.state = State::Selected;
-                self.interrupt = INT_FC;
-                self.aux_stat = PHASE_CMND;
-                // Schedule again for next phase transition
-                schedule!(ServiceKey::Scsi, Duration::from_millis(750));
-                set_irq(SCSI_INT);
-            }
-            // A target has been selected and we are an initiator.
-            State::Selected => {
-                // This will vary depending on what the selected target
-                // device is. For now, we're pretending that ID 0 is a
-                // hard disk device.
-                info!("[SELECTED->COMMAND]");
-                self.state = State::Command;
-                self.interrupt = INT_BUS_SVC;
-                self.aux_stat = PHASE_CMND;
-                set_irq(SCSI_INT);
+            State::Selecting => match self.selected {
+                Some(id) => {
+                    debug!("[SELECTING->SELECTED] id={}", id);
+                    self.state = State::Selected;
+                    self.source_id = ID_VALID | id as u8;
+                    self.raise(INT_FC);
+                    // Schedule again for the target's first phase
+                    schedule!(ServiceKey::Scsi, SELECT_DELAY);
+                }
+                None => {
+                    debug!("[SELECTING->BUSFREE] selection timeout");
+                    self.end_transaction(Some("SELECTION TIMEOUT"));
+                    self.state = State::BusFree;
+                    self.raise(INT_DIS);
+                }
+            },
+            State::Reselecting => {
+                debug!("[RESELECTING->BUSFREE] reselection timeout");
+                self.state = State::BusFree;
+                self.raise(INT_DIS);
             }
-            State::Command => {
-                info!("[COMMAND->DATI]");
-                self.state = State::Data;
-                self.interrupt = INT_BUS_SVC;
-                self.aux_stat = PHASE_DATO;
-                set_irq(SCSI_INT);
+            State::Selected => {
+                self.cmd_ptr = 0;
+                self.enter(State::Command);
             }
-            _ => info!("[???->???]"),
+            State::Executing => self.execute(),
+            State::Writing => self.write_done(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{Disk, BLOCK_SIZE};
+    use crate::mem::Memory;
+    use std::sync::{Arc, Mutex};
+
+    const ADDR_DATA1: usize = RegAddr::Data1 as usize;
+    const ADDR_COMMAND: usize = RegAddr::Command as usize;
+    const ADDR_DEST_ID: usize = RegAddr::DestId as usize;
+    const ADDR_INTERRUPT: usize = RegAddr::Interrupt as usize;
+    const ADDR_AUX: usize = RegAddr::AuxStatus as usize;
+
+    fn with_scsi<T>(name: &str, test: T)
+    where
+        T: FnOnce(&mut Scsi, &mut Bus),
+    {
+        let path = std::env::temp_dir().join(format!("tek4404-scsi-{name}.img"));
+        let mut image = vec![0; 16 * BLOCK_SIZE];
+        for (i, b) in image.iter_mut().enumerate() {
+            *b = (i / BLOCK_SIZE) as u8;
         }
+        std::fs::write(&path, image).unwrap();
+
+        let mut scsi = Scsi::new();
+        let mut bus = Bus::new();
+        scsi.attach(
+            0,
+            Box::new(Disk::open(path.to_str().unwrap(), false, None).unwrap()),
+        )
+        .unwrap();
+        test(&mut scsi, &mut bus);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn set_xfer(scsi: &mut Scsi, bus: &mut Bus, count: u32) {
+        scsi.write_8(bus, RegAddr::Xfer2 as usize, (count >> 16) as u8)
+            .unwrap();
+        scsi.write_8(bus, RegAddr::Xfer1 as usize, (count >> 8) as u8)
+            .unwrap();
+        scsi.write_8(bus, RegAddr::Xfer0 as usize, count as u8)
+            .unwrap();
+    }
+
+    fn transfer_info(scsi: &mut Scsi, bus: &mut Bus, count: u32) {
+        set_xfer(scsi, bus, count);
+        scsi.write_8(bus, ADDR_COMMAND, Command::TransferInfo as u8)
+            .unwrap();
+    }
+
+    fn interrupt(scsi: &mut Scsi, bus: &mut Bus) -> u8 {
+        scsi.read_8(bus, ADDR_INTERRUPT).unwrap()
+    }
+
+    #[test]
+    fn test_selection_timeout() {
+        with_scsi("timeout", |scsi, bus| {
+            scsi.write_8(bus, ADDR_DEST_ID, 3).unwrap();
+            scsi.write_8(bus, ADDR_COMMAND, Command::SelectWithoutAtn as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            assert_eq!(INT_DIS, interrupt(scsi, bus));
+        });
+    }
+
+    #[test]
+    fn test_read_transaction() {
+        with_scsi("read", |scsi, bus| {
+            scsi.write_8(bus, ADDR_DEST_ID, 0).unwrap();
+            scsi.write_8(bus, ADDR_COMMAND, Command::SelectWithoutAtn as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            assert_eq!(INT_FC, interrupt(scsi, bus));
+            scsi.service(bus, 0);
+            assert_eq!(INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_CMND, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+
+            // READ(6) of one block at LBA 5
+            transfer_info(scsi, bus, 6);
+            for b in [0x08, 0, 0, 5, 1, 0] {
+                scsi.write_8(bus, ADDR_DATA1, b).unwrap();
+            }
+            assert_eq!(INT_FC, interrupt(scsi, bus));
+            scsi.service(bus, 0);
+            assert_eq!(INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_DATI, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+
+            transfer_info(scsi, bus, BLOCK_SIZE as u32);
+            for _ in 0..BLOCK_SIZE {
+                assert_ne!(0, scsi.read_8(bus, ADDR_AUX).unwrap() & AUX_DRF);
+                assert_eq!(5, scsi.read_8(bus, ADDR_DATA1).unwrap());
+            }
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_STAT, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+
+            transfer_info(scsi, bus, 1);
+            assert_eq!(STATUS_GOOD, scsi.read_8(bus, ADDR_DATA1).unwrap());
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+
+            transfer_info(scsi, bus, 1);
+            assert_eq!(MSG_COMMAND_COMPLETE, scsi.read_8(bus, ADDR_DATA1).unwrap());
+            assert_eq!(INT_FC, interrupt(scsi, bus));
+
+            scsi.write_8(bus, ADDR_COMMAND, Command::MessageAccepted as u8)
+                .unwrap();
+            assert_eq!(INT_DIS, interrupt(scsi, bus));
+        });
+    }
+
+    #[test]
+    fn test_dma_read() {
+        with_scsi("dma", |scsi, bus| {
+            bus.map_rom = false;
+            bus.ram = Some(Arc::new(Mutex::new(
+                Memory::new(RAM_START, RAM_END, RAM_SIZE, false).unwrap(),
+            )));
+
+            scsi.write_8(bus, ADDR_DEST_ID, 0).unwrap();
+            scsi.write_8(bus, ADDR_COMMAND, Command::SelectWithoutAtn as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            scsi.service(bus, 0);
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+
+            // READ(10) of two blocks at LBA 7, with the CDB itself
+            // sent by DMA from address 0x1000.
+            let cdb = [0x28, 0, 0, 0, 0, 7, 0, 0, 2, 0];
+            for (i, b) in cdb.iter().enumerate() {
+                bus.write_8(FunctionCode::SUPERVISOR_DATA, 0x1000 + i, *b)
+                    .unwrap();
+            }
+            scsi.write_32(bus, RegAddr::Address as usize, 0x1000)
+                .unwrap();
+            set_xfer(scsi, bus, cdb.len() as u32);
+            scsi.write_8(bus, ADDR_COMMAND, CMD_DMA | Command::TransferInfo as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            assert_eq!(INT_FC, interrupt(scsi, bus));
+            assert_ne!(
+                0,
+                scsi.read_8(bus, RegAddr::DmaControl as usize).unwrap() & 0x80
+            );
+            scsi.service(bus, 0);
+            assert_eq!(INT_BUS_SVC, interrupt(scsi, bus));
+
+            scsi.write_32(bus, RegAddr::Address as usize, 0x2000)
+                .unwrap();
+            set_xfer(scsi, bus, 2 * BLOCK_SIZE as u32);
+            scsi.write_8(bus, ADDR_COMMAND, CMD_DMA | Command::TransferInfo as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(0x2000 + 2 * BLOCK_SIZE as u32, scsi.dma.address());
+            assert_eq!(
+                7,
+                bus.read_8(FunctionCode::SUPERVISOR_DATA, 0x2000).unwrap()
+            );
+            assert_eq!(
+                8,
+                bus.read_8(FunctionCode::SUPERVISOR_DATA, 0x2000 + BLOCK_SIZE)
+                    .unwrap()
+            );
+            assert_eq!(PHASE_STAT, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+        });
+    }
+
+    #[test]
+    fn test_target_commands_are_invalid() {
+        with_scsi("invalid", |scsi, bus| {
+            scsi.write_8(bus, ADDR_COMMAND, Command::TxStatus as u8)
+                .unwrap();
+            assert_eq!(INT_INVALID, interrupt(scsi, bus));
+            scsi.write_8(bus, ADDR_COMMAND, Command::TransferInfo as u8)
+                .unwrap();
+            assert_eq!(INT_INVALID, interrupt(scsi, bus));
+        });
+    }
+
+    #[test]
+    fn test_atn_abort() {
+        with_scsi("abort", |scsi, bus| {
+            scsi.write_8(bus, ADDR_DEST_ID, 0).unwrap();
+            scsi.write_8(bus, ADDR_COMMAND, Command::SelectWithAtn as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            scsi.service(bus, 0);
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_MSGO, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+
+            // Identify moves on to the Command phase
+            transfer_info(scsi, bus, 1);
+            scsi.write_8(bus, ADDR_DATA1, 0x80).unwrap();
+            assert_eq!(INT_FC | INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_CMND, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+
+            // Raising ATN and sending Abort frees the bus
+            scsi.write_8(bus, ADDR_COMMAND, Command::SetAtn as u8)
+                .unwrap();
+            transfer_info(scsi, bus, 6);
+            for b in [0, 0, 0, 0, 0, 0] {
+                scsi.write_8(bus, ADDR_DATA1, b).unwrap();
+            }
+            assert_eq!(INT_FC, interrupt(scsi, bus));
+            scsi.service(bus, 0);
+            assert_eq!(INT_BUS_SVC, interrupt(scsi, bus));
+            assert_eq!(PHASE_MSGO, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+            transfer_info(scsi, bus, 1);
+            scsi.write_8(bus, ADDR_DATA1, MSG_ABORT).unwrap();
+            assert_eq!(INT_DIS, interrupt(scsi, bus));
+            assert_eq!(0, scsi.read_8(bus, ADDR_AUX).unwrap() & PHASE_MSGI);
+        });
+    }
+
+    #[test]
+    fn test_target_config() {
+        assert_eq!(
+            Ok(TargetConfig {
+                id: 2,
+                kind: TargetKind::Disk,
+                read_only: false,
+                overlay: None,
+                create: false,
+                path: String::from("c:/scratch.img"),
+            }),
+            "2:disk:c:/scratch.img".parse()
+        );
+        assert_eq!(
+            Ok(TargetConfig {
+                id: 4,
+                kind: TargetKind::Tape,
+                read_only: true,
+                overlay: None,
+                create: false,
+                path: String::from("dist.tap"),
+            }),
+            "4:tape,ro:dist.tap".parse()
+        );
+        assert_eq!(
+            Ok(TargetConfig {
+                id: 4,
+                kind: TargetKind::Tape,
+                read_only: false,
+                overlay: None,
+                create: true,
+                path: String::from("backup.tap"),
+            }),
+            "4:tape,create:backup.tap".parse()
+        );
+        assert_eq!(
+            Ok(TargetConfig {
+                id: 0,
+                kind: TargetKind::Disk,
+                read_only: false,
+                overlay: Some(OverlayMode::Sidecar(String::from("golden.cow"))),
+                create: false,
+                path: String::from("golden.img"),
+            }),
+            "0:disk,overlay=golden.cow:golden.img".parse()
+        );
+        assert!("4:tape,rw:dist.tap".parse::<TargetConfig>().is_err());
+        assert!("4:tape,overlay:dist.tap".parse::<TargetConfig>().is_err());
+        assert!("0:disk,create:x.img".parse::<TargetConfig>().is_err());
+        assert!("7:disk:x.img".parse::<TargetConfig>().is_err());
+        assert!("0:floppy:x.img".parse::<TargetConfig>().is_err());
+        assert!("0:disk".parse::<TargetConfig>().is_err());
+    }
+
+    #[test]
+    fn test_selection_timeout_from_counter() {
+        let mut scsi = Scsi::new();
+        scsi.xfer = 0x262600;
+        assert_eq!(SELECT_TIMEOUT_TICK * 0x2626, scsi.select_timeout());
+        scsi.xfer = 0x0000ff;
+        assert_eq!(SELECT_TIMEOUT_TICK * 0x10000, scsi.select_timeout());
+    }
+
+    #[test]
+    fn test_injected_faults() {
+        with_scsi("faults", |scsi, bus| {
+            scsi.add_fault("0 timeout times=1".parse().unwrap())
+                .unwrap();
+            scsi.add_fault("0 unit-attention".parse().unwrap()).unwrap();
+            scsi.add_fault("0 busy times=1".parse().unwrap()).unwrap();
+            scsi.add_fault("0 medium-error 5".parse().unwrap()).unwrap();
+            assert!(scsi.add_fault("1 busy".parse().unwrap()).is_err());
+
+            let path = std::env::temp_dir().join("tek4404-scsi-faults.tap");
+            let tape = Tape::open(path.to_str().unwrap(), false, true).unwrap();
+            scsi.attach(4, Box::new(tape)).unwrap();
+            assert!(scsi.add_fault("4 busy".parse().unwrap()).is_ok());
+            assert!(scsi.add_fault("4 medium-error 5".parse().unwrap()).is_err());
+            let _ = std::fs::remove_file(&path);
+
+            scsi.write_8(bus, ADDR_DEST_ID, 0).unwrap();
+            scsi.write_8(bus, ADDR_COMMAND, Command::SelectWithoutAtn as u8)
+                .unwrap();
+            scsi.service(bus, 0);
+            assert_eq!(INT_DIS, interrupt(scsi, bus));
+
+            let unit = scsi.units[0].as_mut().unwrap();
+            let read = [0x08, 0, 0, 4, 2, 0];
+            assert_eq!(STATUS_BUSY, unit.command(&read).0);
+            assert_eq!(STATUS_CHECK, unit.command(&read).0);
+            assert_eq!(SENSE_UNIT_ATTENTION, unit.sense.key);
+            assert_eq!(STATUS_CHECK, unit.command(&read).0);
+            assert_eq!(SENSE_MEDIUM_ERROR, unit.sense.key);
+
+            match unit.command(&[0x03, 0, 0, 0, 18, 0]) {
+                (STATUS_GOOD, Transfer::In(data)) => {
+                    assert_eq!(0xf0, data[0]);
+                    assert_eq!(SENSE_MEDIUM_ERROR, data[2]);
+                    assert_eq!([0, 0, 0, 5], data[3..7]);
+                }
+                _ => panic!("bad request sense response"),
+            }
+            assert_eq!(STATUS_GOOD, unit.command(&[0x08, 0, 0, 6, 1, 0]).0);
+
+            // A controller reset brings the UNIT ATTENTION back.
+            scsi.reset();
+            let unit = scsi.units[0].as_mut().unwrap();
+            assert_eq!(STATUS_CHECK, unit.command(&[0, 0, 0, 0, 0, 0]).0);
+        });
+    }
+
+    #[test]
+    fn test_request_sense_after_check_condition() {
+        with_scsi("sense", |scsi, _bus| {
+            let unit = scsi.units[0].as_mut().unwrap();
+            let (status, _) = unit.command(&[0x08, 0, 0, 100, 1, 0]);
+            assert_eq!(STATUS_CHECK, status);
+
+            match unit.command(&[0x03, 0, 0, 0, 18, 0]) {
+                (STATUS_GOOD, Transfer::In(data)) => {
+                    assert_eq!(SENSE_ILLEGAL_REQUEST, data[2]);
+                    assert_eq!(ASC_LBA_OUT_OF_RANGE, data[12]);
+                }
+                _ => panic!("bad request sense response"),
+            }
+        });
     }
 }
@@ -0,0 +1,212 @@
+//! SDL display and keyboard
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::err::SimError;
+use crate::frontend::{Frontend, Input};
+use crate::video::{Screen, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+use log::error;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use sdl2::EventPump;
+use std::ops::Range;
+
+// TODO: This map is incomplete, and it's been derived by
+// trial-and-error.
+fn map_keycode(k: &Keycode) -> u8 {
+    match *k {
+        Keycode::LShift => 0x01,
+        Keycode::RShift => 0x02,
+        Keycode::Return => 0x05,
+        Keycode::Backspace => 0x06,
+        Keycode::Tab => 0x07,
+        Keycode::Escape => 0x0a,
+        Keycode::Space => 0x0b,
+        Keycode::Quote => 0x0c,
+        Keycode::Comma => 0x0d,
+        Keycode::Minus => 0x0e,
+        Keycode::Period => 0x0f,
+        Keycode::Slash => 0x10,
+        Keycode::Num0 => 0x11,
+        Keycode::Num1 => 0x12,
+        Keycode::Num2 => 0x13,
+        Keycode::Num3 => 0x14,
+        Keycode::Num4 => 0x15,
+        Keycode::Num5 => 0x16,
+        Keycode::Num6 => 0x17,
+        Keycode::Num7 => 0x18,
+        Keycode::Num8 => 0x19,
+        Keycode::Num9 => 0x1a,
+        Keycode::Semicolon => 0x1b,
+        Keycode::Equals => 0x1c,
+        Keycode::A => 0x1d,
+        Keycode::B => 0x1e,
+        Keycode::C => 0x1f,
+        Keycode::D => 0x20,
+        Keycode::E => 0x21,
+        Keycode::F => 0x22,
+        Keycode::G => 0x23,
+        Keycode::H => 0x24,
+        Keycode::I => 0x25,
+        Keycode::J => 0x26,
+        Keycode::K => 0x27,
+        Keycode::L => 0x28,
+        Keycode::M => 0x29,
+        Keycode::N => 0x2a,
+        Keycode::O => 0x2b,
+        Keycode::P => 0x2c,
+        Keycode::Q => 0x2d,
+        Keycode::R => 0x2e,
+        Keycode::S => 0x2f,
+        Keycode::T => 0x30,
+        Keycode::U => 0x31,
+        Keycode::V => 0x32,
+        Keycode::W => 0x33,
+        Keycode::X => 0x34,
+        Keycode::Y => 0x35,
+        Keycode::Z => 0x36,
+        Keycode::LeftBracket => 0x37,
+        Keycode::Backslash => 0x38,
+        Keycode::RightBracket => 0x39,
+        Keycode::Delete => 0x3b,
+        Keycode::KpEnter => 0x3c,
+        Keycode::KpComma => 0x3d,
+        Keycode::KpMinus => 0x3e,
+        Keycode::KpPeriod => 0x3f,
+        Keycode::Kp0 => 0x40,
+        Keycode::Kp1 => 0x41,
+        Keycode::Kp2 => 0x42,
+        Keycode::Kp3 => 0x43,
+        Keycode::Kp4 => 0x44,
+        Keycode::Kp5 => 0x45,
+        Keycode::Kp6 => 0x46,
+        Keycode::Kp7 => 0x47,
+        Keycode::Kp8 => 0x48,
+        Keycode::Kp9 => 0x49,
+        _ => 0x03,
+    }
+}
+
+/// The display in a window, with the host's keyboard
+pub struct Sdl {
+    canvas: Canvas<Window>,
+    /// The screen, as last shown
+    texture: Texture,
+    event_pump: EventPump,
+}
+
+impl Sdl {
+    /// Open the window.
+    pub fn new() -> Result<Self, SimError> {
+        let sdl_context = sdl2::init().map_err(SimError::Init)?;
+        let video_subsystem = sdl_context.video().map_err(SimError::Init)?;
+
+        let window = video_subsystem
+            .window(
+                "Tektronix 4404",
+                DISPLAY_WIDTH as u32,
+                DISPLAY_HEIGHT as u32,
+            )
+            .build()
+            .map_err(|e| SimError::Init(e.to_string()))?;
+
+        let canvas = window
+            .into_canvas()
+            .present_vsync()
+            .build()
+            .map_err(|e| SimError::Init(e.to_string()))?;
+        // Built with unsafe_textures, so the texture doesn't borrow its
+        // creator. It is freed along with the canvas.
+        let texture = canvas
+            .texture_creator()
+            .create_texture_target(
+                PixelFormatEnum::RGB332,
+                DISPLAY_WIDTH as u32,
+                DISPLAY_HEIGHT as u32,
+            )
+            .map_err(|e| SimError::Init(e.to_string()))?;
+        let event_pump = sdl_context.event_pump().map_err(SimError::Init)?;
+
+        Ok(Sdl {
+            canvas,
+            texture,
+            event_pump,
+        })
+    }
+
+    fn repaint(&mut self) {
+        self.canvas.clear();
+        if let Err(e) = self.canvas.copy(&self.texture, None, None) {
+            error!("Couldn't copy texture to canvas: {}", e);
+        }
+        self.canvas.present();
+    }
+}
+
+impl Frontend for Sdl {
+    fn frame(&mut self, screen: &Screen, changed: Range<usize>) {
+        let area = Rect::new(
+            0,
+            changed.start as i32,
+            DISPLAY_WIDTH as u32,
+            changed.len() as u32,
+        );
+        let pixels = &screen.pixels[changed.start * DISPLAY_WIDTH..changed.end * DISPLAY_WIDTH];
+        match self.texture.update(area, pixels, DISPLAY_WIDTH) {
+            Ok(()) => self.repaint(),
+            Err(e) => error!("Couldn't copy framebuffer to texture: {}", e),
+        }
+    }
+
+    fn poll(&mut self) -> Option<Vec<Input>> {
+        let mut input = Vec::new();
+        let mut repaint = false;
+
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => return None,
+                Event::KeyDown {
+                    keycode: Some(k), ..
+                } => input.push(Input::KeyDown(map_keycode(&k))),
+                Event::KeyUp {
+                    keycode: Some(k), ..
+                } => input.push(Input::KeyUp(map_keycode(&k))),
+                Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                } => repaint = true,
+                _ => {}
+            }
+        }
+
+        if repaint {
+            self.repaint();
+        }
+        Some(input)
+    }
+}
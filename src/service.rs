@@ -22,25 +22,46 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 //
+use crate::cpu;
+
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use tokio::time::{Duration, Instant};
+use std::time::Duration;
+
+// Service requests are scheduled in emulated time, counted in CPU
+// clock cycles since power on. The CPU runs up to the next pending
+// request and stops, so devices are serviced at the same point in
+// the guest's execution however fast or loaded the host is.
+//
+// A request names the device to service and a tag, which the device
+// is handed when it is serviced and may use to tell its own events
+// apart. A device has at most one request pending per tag: making a
+// request again moves it rather than adding a second one.
 
 /// Device types that may be intermittently serviced
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum ServiceKey {
     Scsi,
+    Video,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct ServiceRequest {
     pub key: ServiceKey,
-    pub when: Instant,
+    pub tag: u8,
+    /// The cycle the request is due on
+    pub when: u64,
+    /// Requests due on the same cycle are serviced in the order they
+    /// were made.
+    seq: u64,
 }
 
 impl Ord for ServiceRequest {
     fn cmp(&self, other: &ServiceRequest) -> Ordering {
-        other.when.cmp(&self.when)
+        other
+            .when
+            .cmp(&self.when)
+            .then_with(|| other.seq.cmp(&self.seq))
     }
 }
 
@@ -50,8 +71,16 @@ impl PartialOrd for ServiceRequest {
     }
 }
 
+/// The number of CPU cycles in `delay`, rounded up
+pub fn cycles(delay: Duration) -> u64 {
+    (delay.as_nanos() * cpu::CLOCK_HZ as u128).div_ceil(1_000_000_000) as u64
+}
+
 pub struct ServiceQueue {
     pub queue: BinaryHeap<ServiceRequest>,
+    /// Cycles run before the current CPU time slice
+    now: u64,
+    seq: u64,
 }
 
 impl Default for ServiceQueue {
@@ -64,20 +93,134 @@ impl ServiceQueue {
     pub fn new() -> Self {
         ServiceQueue {
             queue: BinaryHeap::new(),
+            now: 0,
+            seq: 0,
         }
     }
 
-    pub fn schedule(&mut self, key: ServiceKey, delay: Duration) {
+    /// The current emulated time, in cycles. Requests made from
+    /// inside a time slice count from the instruction making them.
+    pub fn now(&self) -> u64 {
+        self.now + cpu::cycles_run()
+    }
+
+    /// Request service for the device `key` after `delay`, replacing
+    /// any request already pending with the same tag.
+    pub fn schedule(&mut self, key: ServiceKey, tag: u8, delay: Duration) {
+        let delay = cycles(delay);
+        self.cancel(key, tag);
+        self.seq += 1;
         self.queue.push(ServiceRequest {
             key,
-            when: Instant::now() + delay,
+            tag,
+            when: self.now() + delay,
+            seq: self.seq,
         });
+        // A request made by the running CPU may fall due before its
+        // time slice was to end.
+        cpu::end_slice_within(delay);
+    }
+
+    /// Withdraw the pending request with this tag, if there is one.
+    pub fn cancel(&mut self, key: ServiceKey, tag: u8) {
+        self.queue.retain(|srq| srq.key != key || srq.tag != tag);
+    }
+
+    /// Account for a time slice the CPU has finished running.
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    /// The number of cycles until the next request is due, if any
+    /// are pending.
+    pub fn until_next(&self) -> Option<u64> {
+        self.queue
+            .peek()
+            .map(|srq| srq.when.saturating_sub(self.now))
     }
 
     pub fn take(&mut self) -> Option<ServiceRequest> {
         match self.queue.peek() {
-            Some(srq) if Instant::now() > srq.when => self.queue.pop(),
+            Some(srq) if srq.when <= self.now => self.queue.pop(),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{BUS, ROM_END, ROM_SIZE, ROM_START};
+    use crate::cpu::Cpu;
+    use crate::mem::Memory;
+    use crate::scsi::Scsi;
+    use crate::throttle;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_emulated_time() {
+        let mut queue = ServiceQueue::new();
+        assert_eq!(None, queue.until_next());
+
+        // 10 MHz
+        assert_eq!(1000, cycles(Duration::from_micros(100)));
+        assert_eq!(5, cycles(Duration::from_nanos(500)));
+
+        queue.schedule(ServiceKey::Scsi, 0, Duration::from_micros(100));
+        queue.schedule(ServiceKey::Scsi, 1, Duration::from_micros(20));
+        assert_eq!(Some(200), queue.until_next());
+
+        queue.advance(199);
+        assert_eq!(None, queue.take());
+        queue.advance(1);
+        assert_eq!(200, queue.take().unwrap().when);
+        assert_eq!(Some(800), queue.until_next());
+        assert_eq!(None, queue.take());
+    }
+
+    #[test]
+    fn test_request_ends_slice() {
+        // Select an empty SCSI ID with the shortest timeout, 256
+        // cycles, then loop.
+        #[rustfmt::skip]
+        let program: [u8; 26] = [
+            0x00, 0x00, 0x10, 0x00, // SSP
+            0x00, 0x74, 0x00, 0x08, // PC
+            0x13, 0xfc, 0x00, 0x01, 0x00, 0x7b, 0xe0, 0x1a, // move.b #1,Xfer1
+            0x13, 0xfc, 0x00, 0x09, 0x00, 0x7b, 0xe0, 0x02, // move.b #9,Command
+            0x60, 0xfe, // bra.s *
+        ];
+        let mut rom = Memory::new(ROM_START, ROM_END, ROM_SIZE, true).unwrap();
+        let mut data = vec![0; ROM_SIZE];
+        data[..program.len()].copy_from_slice(&program);
+        rom.load(&data);
+        {
+            let mut bus = BUS.lock().unwrap();
+            bus.rom = Some(Arc::new(Mutex::new(rom)));
+            bus.scsi = Some(Arc::new(Mutex::new(Scsi::new())));
+        }
+
+        // The timeout is requested during the second move, 20 cycles
+        // in, and the slice ends with the first instruction to reach
+        // it rather than running on for all of its 10ms.
+        let mut cpu = Cpu::new();
+        let ran = cpu.execute(throttle::SLICE);
+        assert!((276..286).contains(&ran), "ran {ran} cycles");
+    }
+
+    #[test]
+    fn test_replace_and_cancel() {
+        let mut queue = ServiceQueue::new();
+        queue.schedule(ServiceKey::Scsi, 0, Duration::from_micros(100));
+        queue.schedule(ServiceKey::Scsi, 0, Duration::from_micros(10));
+        queue.schedule(ServiceKey::Scsi, 1, Duration::from_micros(50));
+        assert_eq!(2, queue.queue.len());
+        assert_eq!(Some(100), queue.until_next());
+
+        queue.cancel(ServiceKey::Scsi, 0);
+        assert_eq!(1, queue.queue.len());
+        queue.advance(500);
+        assert_eq!(1, queue.take().unwrap().tag);
+        assert_eq!(None, queue.take());
+    }
+}
@@ -0,0 +1,673 @@
+//! SCSI streaming tape target
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::err::SimError;
+use crate::scsi::*;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::{debug, error};
+use num_traits::FromPrimitive;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// A SIMH tape image is a sequence of records. Each data record is a
+// 32-bit little-endian length, the data itself padded to an even
+// number of bytes, and the length repeated as a trailer so the tape
+// can be read backwards. A length of zero is a tape mark.
+//
+//   0x00000000: Tape mark
+//   0xfffffffe: Erase gap, skipped
+//   0xffffffff: End of medium
+//
+// Bit 31 of a data record's length marks a record that was recorded
+// with an error. The end of the file is the end of recorded data.
+
+const MARK_GAP: u32 = 0xfffffffe;
+const MARK_EOM: u32 = 0xffffffff;
+const LEN_ERROR: u32 = 0x80000000;
+const LEN_MASK: u32 = 0x00ffffff;
+
+/// Default block length for fixed-block transfers
+const DEFAULT_BLOCK_SIZE: u32 = 512;
+/// Largest record the drive reports through READ BLOCK LIMITS
+const MAX_BLOCK_SIZE: u32 = 0xffffff;
+
+const INQUIRY_LEN: usize = 36;
+const VENDOR: &[u8; 8] = b"TEKTRONX";
+const PRODUCT: &[u8; 16] = b"4404 TAPE DRIVE ";
+const REVISION: &[u8; 4] = b"1.0 ";
+
+/// What the head found when it moved over the next record.
+#[derive(Debug, PartialEq, Eq)]
+enum Record {
+    Data(Vec<u8>),
+    Mark,
+    /// Beginning of tape, when moving backwards
+    Bot,
+    /// End of recorded data
+    Eod,
+    /// End of medium marker
+    Eom,
+}
+
+/// Sense data carrying a residue in the information field.
+fn sense(key: u8, ascq: u8, flags: u8, residue: i32) -> Sense {
+    Sense {
+        key,
+        asc: ASC_NONE,
+        ascq,
+        flags,
+        info: Some(residue as u32),
+    }
+}
+
+/// Sense data for a movement that stopped early on `record`, with
+/// `residue` blocks, marks, or bytes left undone.
+fn stopped(record: &Record, residue: i32) -> Sense {
+    match record {
+        Record::Mark => sense(SENSE_NONE, ASCQ_FILEMARK, SENSE_FILEMARK, residue),
+        Record::Bot => sense(SENSE_NONE, ASCQ_BEGINNING_OF_MEDIUM, SENSE_EOM, residue),
+        Record::Eom => sense(SENSE_MEDIUM_ERROR, ASCQ_END_OF_MEDIUM, SENSE_EOM, residue),
+        _ => sense(SENSE_BLANK_CHECK, ASCQ_END_OF_DATA, 0, residue),
+    }
+}
+
+/// A 24-bit two's complement count, as used by SPACE.
+fn signed_count(cdb: &[u8]) -> i32 {
+    ((BigEndian::read_u24(&cdb[2..5]) << 8) as i32) >> 8
+}
+
+/// A streaming tape drive backed by a SIMH `.tap` image on the host.
+pub struct Tape {
+    file: File,
+    /// Offset of the head in the image
+    pos: u64,
+    read_only: bool,
+    /// Block length for fixed-block transfers, or 0 for variable
+    block_size: u32,
+    /// CHECK CONDITION to report once the current command finishes
+    check: Option<Sense>,
+}
+
+impl Tape {
+    /// Open the image at `path`. If `create` is set, an empty tape is
+    /// created if it does not exist. The tape is write-protected if
+    /// `read_only` is set, or if the host does not permit writing to
+    /// it.
+    pub fn open(path: &str, read_only: bool, create: bool) -> Result<Tape, SimError> {
+        let writable = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create && !read_only)
+            .truncate(false)
+            .open(path);
+        let (file, read_only) = match writable {
+            Ok(f) if !read_only => (f, false),
+            _ => match File::open(path) {
+                Ok(f) => (f, true),
+                Err(e) => return Err(SimError::Init(format!("{path}: {e}"))),
+            },
+        };
+
+        Ok(Tape {
+            file,
+            pos: 0,
+            read_only,
+            block_size: DEFAULT_BLOCK_SIZE,
+            check: None,
+        })
+    }
+
+    fn read_len(&mut self, offset: u64) -> io::Result<Option<u32>> {
+        let mut buf = [0; 4];
+        self.file.seek(SeekFrom::Start(offset))?;
+        match self.file.read_exact(&mut buf) {
+            Ok(()) => Ok(Some(LittleEndian::read_u32(&buf))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_failed(&self, e: io::Error) -> Sense {
+        error!("Tape read failed at offset {}: {}", self.pos, e);
+        Sense::new(SENSE_MEDIUM_ERROR, ASC_UNRECOVERED_READ)
+    }
+
+    /// Move forward over the next record, returning its contents.
+    fn next(&mut self) -> Result<Record, Sense> {
+        loop {
+            let len = match self.read_len(self.pos).map_err(|e| self.read_failed(e))? {
+                None => return Ok(Record::Eod),
+                Some(len) => len,
+            };
+
+            match len {
+                0 => {
+                    self.pos += 4;
+                    return Ok(Record::Mark);
+                }
+                MARK_GAP => self.pos += 4,
+                MARK_EOM => return Ok(Record::Eom),
+                _ => {
+                    let size = (len & LEN_MASK) as usize;
+                    let mut data = vec![0; size];
+                    self.file
+                        .read_exact(&mut data)
+                        .map_err(|e| self.read_failed(e))?;
+                    self.pos += 8 + (size + (size & 1)) as u64;
+                    if len & LEN_ERROR != 0 {
+                        return Err(Sense::new(SENSE_MEDIUM_ERROR, ASC_UNRECOVERED_READ));
+                    }
+                    return Ok(Record::Data(data));
+                }
+            }
+        }
+    }
+
+    /// Move backward over the previous record. The contents of a data
+    /// record are not read.
+    fn prev(&mut self) -> Result<Record, Sense> {
+        loop {
+            if self.pos < 4 {
+                self.pos = 0;
+                return Ok(Record::Bot);
+            }
+
+            let len = self
+                .read_len(self.pos - 4)
+                .map_err(|e| self.read_failed(e))?
+                .unwrap_or(0);
+
+            match len {
+                0 => {
+                    self.pos -= 4;
+                    return Ok(Record::Mark);
+                }
+                MARK_GAP => self.pos -= 4,
+                _ => {
+                    let size = (len & LEN_MASK) as u64;
+                    self.pos = self.pos.saturating_sub(8 + size + (size & 1));
+                    return Ok(Record::Data(vec![]));
+                }
+            }
+        }
+    }
+
+    fn write_protect(&self) -> Result<(), Sense> {
+        if self.read_only {
+            Err(Sense::new(SENSE_DATA_PROTECT, ASC_WRITE_PROTECTED))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Append `bytes` at the head. Anything recorded beyond the head is
+    /// lost, as on a real tape.
+    fn record(&mut self, bytes: &[u8]) -> Result<(), Sense> {
+        self.write_protect()?;
+        self.file
+            .seek(SeekFrom::Start(self.pos))
+            .and_then(|_| self.file.write_all(bytes))
+            .and_then(|_| self.file.set_len(self.pos + bytes.len() as u64))
+            .map_err(|e| {
+                error!("Tape write failed at offset {}: {}", self.pos, e);
+                Sense::new(SENSE_MEDIUM_ERROR, ASC_WRITE_FAULT)
+            })?;
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, data: &[u8]) -> Result<(), Sense> {
+        let mut len = [0; 4];
+        LittleEndian::write_u32(&mut len, data.len() as u32);
+
+        let mut buf = Vec::with_capacity(data.len() + 9);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(data);
+        if data.len() & 1 != 0 {
+            buf.push(0);
+        }
+        buf.extend_from_slice(&len);
+        self.record(&buf)
+    }
+
+    fn read(&mut self, cdb: &[u8]) -> Result<Vec<u8>, Sense> {
+        let fixed = cdb[1] & 1 != 0;
+        let sili = cdb[1] & 2 != 0;
+        let count = BigEndian::read_u24(&cdb[2..5]) as i32;
+
+        if !fixed {
+            debug!("READ variable length={}", count);
+            // A transfer length of zero reads nothing, and leaves the
+            // tape where it is.
+            if count == 0 {
+                return Ok(Vec::new());
+            }
+            return match self.next()? {
+                Record::Data(mut data) => {
+                    if data.len() as i32 != count && !sili {
+                        let residue = count - data.len() as i32;
+                        self.check = Some(sense(SENSE_NONE, 0, SENSE_ILI, residue));
+                    }
+                    data.truncate(count as usize);
+                    Ok(data)
+                }
+                record => Err(stopped(&record, count)),
+            };
+        }
+
+        if self.block_size == 0 {
+            return Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD));
+        }
+
+        debug!("READ fixed blocks={} size={}", count, self.block_size);
+        let size = self.block_size as usize;
+        // The count and block size both come from the initiator, so
+        // the buffer grows as blocks are read rather than being sized
+        // for the whole request up front.
+        let mut buf = Vec::new();
+        for n in 0..count {
+            match self.next()? {
+                Record::Data(mut data) => {
+                    let short = data.len() != size;
+                    data.resize(size, 0);
+                    buf.extend_from_slice(&data);
+                    if short {
+                        self.check = Some(sense(SENSE_NONE, 0, SENSE_ILI, count - n - 1));
+                        break;
+                    }
+                }
+                record => {
+                    let sense = stopped(&record, count - n);
+                    if n == 0 {
+                        return Err(sense);
+                    }
+                    self.check = Some(sense);
+                    break;
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    fn write_length(&self, cdb: &[u8]) -> Result<usize, Sense> {
+        let count = BigEndian::read_u24(&cdb[2..5]) as usize;
+        if cdb[1] & 1 == 0 {
+            Ok(count)
+        } else if self.block_size == 0 {
+            Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD))
+        } else {
+            Ok(count * self.block_size as usize)
+        }
+    }
+
+    fn write(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Sense> {
+        if cdb[1] & 1 == 0 {
+            self.write_record(data)
+        } else {
+            for block in data.chunks(self.block_size as usize) {
+                self.write_record(block)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn write_marks(&mut self, count: u32) -> Result<(), Sense> {
+        debug!("WRITE FILEMARKS count={}", count);
+        self.record(&vec![0; count as usize * 4])
+    }
+
+    fn space(&mut self, cdb: &[u8]) -> Result<(), Sense> {
+        let code = cdb[1] & 7;
+        let count = signed_count(cdb);
+        debug!("SPACE code={} count={}", code, count);
+
+        match code {
+            // Blocks
+            0 => {
+                for n in 0..count.abs() {
+                    let record = if count > 0 {
+                        self.next()?
+                    } else {
+                        self.prev()?
+                    };
+                    if !matches!(record, Record::Data(_)) {
+                        return Err(stopped(&record, count - n * count.signum()));
+                    }
+                }
+                Ok(())
+            }
+            // Filemarks
+            1 => {
+                let mut n = 0;
+                while n < count.abs() {
+                    match if count > 0 {
+                        self.next()?
+                    } else {
+                        self.prev()?
+                    } {
+                        Record::Data(_) => {}
+                        Record::Mark => n += 1,
+                        record => return Err(stopped(&record, count - n * count.signum())),
+                    }
+                }
+                Ok(())
+            }
+            // End of recorded data
+            3 => loop {
+                match self.next()? {
+                    Record::Eod | Record::Eom => return Ok(()),
+                    _ => {}
+                }
+            },
+            _ => Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD)),
+        }
+    }
+
+    fn erase(&mut self, cdb: &[u8]) -> Result<(), Sense> {
+        self.write_protect()?;
+        // A long erase wipes everything from the head to the end of
+        // the tape; a short erase only leaves a gap, which an image
+        // has no need to record.
+        if cdb[1] & 1 != 0 {
+            self.record(&[])?;
+        }
+        Ok(())
+    }
+
+    fn inquiry(&self, cdb: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; INQUIRY_LEN];
+        buf[0] = 1; // Sequential access
+        buf[1] = 0x80; // Removable medium
+        buf[2] = 1; // SCSI-1
+        buf[3] = 1; // CCS response format
+        buf[4] = (INQUIRY_LEN - 5) as u8;
+        buf[8..16].copy_from_slice(VENDOR);
+        buf[16..32].copy_from_slice(PRODUCT);
+        buf[32..36].copy_from_slice(REVISION);
+        buf.truncate(cdb[4] as usize);
+        buf
+    }
+
+    fn read_block_limits(&self) -> Vec<u8> {
+        let mut buf = vec![0; 6];
+        BigEndian::write_u24(&mut buf[1..4], MAX_BLOCK_SIZE);
+        BigEndian::write_u16(&mut buf[4..6], 1);
+        buf
+    }
+
+    fn mode_sense(&self, cdb: &[u8]) -> Vec<u8> {
+        // Mode parameter header, followed by one block descriptor.
+        let mut buf = vec![0; 12];
+        buf[0] = 11;
+        if self.read_only {
+            buf[2] = 0x80;
+        }
+        buf[3] = 8;
+        BigEndian::write_u24(&mut buf[9..12], self.block_size);
+        buf.truncate(cdb[4] as usize);
+        buf
+    }
+
+    fn mode_select(&mut self, data: &[u8]) -> Result<(), Sense> {
+        if data.len() >= 12 && data[3] >= 8 {
+            let block_size = BigEndian::read_u24(&data[9..12]);
+            if block_size > MAX_BLOCK_SIZE {
+                return Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD));
+            }
+            debug!("MODE SELECT block size={}", block_size);
+            self.block_size = block_size;
+        }
+        Ok(())
+    }
+}
+
+/// Data to return to the initiator, if there is any.
+fn data_in(buf: Vec<u8>) -> Transfer {
+    if buf.is_empty() {
+        Transfer::None
+    } else {
+        Transfer::In(buf)
+    }
+}
+
+impl ScsiTarget for Tape {
+    fn command(&mut self, cdb: &[u8]) -> Result<Transfer, Sense> {
+        self.check = None;
+
+        match FromPrimitive::from_u8(cdb[0]) {
+            Some(Op::TestReady)
+            | Some(Op::PreventAllow)
+            | Some(Op::Reserve)
+            | Some(Op::Release)
+            | Some(Op::SendDiag) => Ok(Transfer::None),
+            // LOAD/UNLOAD shares its opcode with START/STOP UNIT, and
+            // both leave the tape at the beginning.
+            Some(Op::Rewind) | Some(Op::StartStop) => {
+                debug!("REWIND");
+                self.pos = 0;
+                Ok(Transfer::None)
+            }
+            Some(Op::Inquiry) => Ok(Transfer::In(self.inquiry(cdb))),
+            Some(Op::ReadBlockLimits) => Ok(Transfer::In(self.read_block_limits())),
+            Some(Op::ModeSense6) => Ok(Transfer::In(self.mode_sense(cdb))),
+            Some(Op::ModeSelect6) => match cdb[4] {
+                0 => Ok(Transfer::None),
+                n => Ok(Transfer::Out(n as usize)),
+            },
+            Some(Op::Read6) => Ok(data_in(self.read(cdb)?)),
+            Some(Op::Write6) => {
+                self.write_protect()?;
+                match self.write_length(cdb)? {
+                    0 => Ok(Transfer::None),
+                    n => Ok(Transfer::Out(n)),
+                }
+            }
+            Some(Op::WriteFileMark) => {
+                self.write_marks(BigEndian::read_u24(&cdb[2..5]))?;
+                Ok(Transfer::None)
+            }
+            Some(Op::Space) => {
+                self.space(cdb)?;
+                Ok(Transfer::None)
+            }
+            Some(Op::Erase) => {
+                self.erase(cdb)?;
+                Ok(Transfer::None)
+            }
+            _ => Err(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_OPCODE)),
+        }
+    }
+
+    fn data_out(&mut self, cdb: &[u8], data: &[u8]) -> Result<(), Sense> {
+        match FromPrimitive::from_u8(cdb[0]) {
+            Some(Op::ModeSelect6) => self.mode_select(data),
+            _ => self.write(cdb, data),
+        }
+    }
+
+    fn check(&mut self) -> Option<Sense> {
+        self.check.take()
+    }
+
+    fn sequential(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const REWIND: [u8; 6] = [0x01, 0, 0, 0, 0, 0];
+    const WRITE_MARK: [u8; 6] = [0x10, 0, 0, 0, 1, 0];
+
+    fn with_tape<T>(name: &str, test: T)
+    where
+        T: FnOnce(&mut Tape, &PathBuf),
+    {
+        let path: PathBuf = std::env::temp_dir().join(format!("tek4404-{name}.tap"));
+        let _ = std::fs::remove_file(&path);
+        let mut tape = Tape::open(path.to_str().unwrap(), false, true).unwrap();
+        test(&mut tape, &path);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn write(tape: &mut Tape, data: &[u8]) {
+        let len = data.len() as u8;
+        let cdb = [0x0a, 0, 0, 0, len, 0];
+        assert_eq!(Ok(Transfer::Out(data.len())), tape.command(&cdb));
+        assert_eq!(Ok(()), tape.data_out(&cdb, data));
+    }
+
+    fn read(tape: &mut Tape, len: u8) -> Result<Transfer, Sense> {
+        tape.command(&[0x08, 0, 0, 0, len, 0])
+    }
+
+    #[test]
+    fn test_open_missing() {
+        let path = std::env::temp_dir().join("tek4404-missing.tap");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        assert!(Tape::open(path, false, false).is_err());
+        assert!(Tape::open(path, true, true).is_err());
+        assert!(Tape::open(path, false, true).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_simh_format() {
+        with_tape("format", |tape, path| {
+            write(tape, b"abc");
+            tape.command(&WRITE_MARK).unwrap();
+            assert_eq!(
+                vec![3, 0, 0, 0, b'a', b'b', b'c', 0, 3, 0, 0, 0, 0, 0, 0, 0],
+                std::fs::read(path).unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn test_read_to_filemark() {
+        with_tape("filemark", |tape, _| {
+            write(tape, b"first");
+            tape.command(&WRITE_MARK).unwrap();
+            write(tape, b"second");
+            tape.command(&REWIND).unwrap();
+
+            assert_eq!(Ok(Transfer::In(b"first".to_vec())), read(tape, 5));
+            assert_eq!(None, tape.check());
+
+            let sense = read(tape, 6).unwrap_err();
+            assert_eq!(SENSE_FILEMARK, sense.flags);
+            assert_eq!(ASCQ_FILEMARK, sense.ascq);
+
+            assert_eq!(Ok(Transfer::In(b"second".to_vec())), read(tape, 6));
+
+            let sense = read(tape, 6).unwrap_err();
+            assert_eq!(SENSE_BLANK_CHECK, sense.key);
+            assert_eq!(ASCQ_END_OF_DATA, sense.ascq);
+        });
+    }
+
+    #[test]
+    fn test_incorrect_length() {
+        with_tape("ili", |tape, _| {
+            write(tape, b"record");
+            tape.command(&REWIND).unwrap();
+
+            assert_eq!(Ok(Transfer::In(b"reco".to_vec())), read(tape, 4));
+            let sense = tape.check().unwrap();
+            assert_eq!(SENSE_ILI, sense.flags);
+            assert_eq!(Some(-2i32 as u32), sense.info);
+        });
+    }
+
+    #[test]
+    fn test_zero_length_and_huge_reads() {
+        with_tape("lengths", |tape, _| {
+            write(tape, b"abc");
+            tape.command(&REWIND).unwrap();
+
+            assert_eq!(Ok(Transfer::None), read(tape, 0));
+            assert_eq!(None, tape.check());
+            assert_eq!(Ok(Transfer::In(b"abc".to_vec())), read(tape, 3));
+
+            // 64KB blocks, then a read of 2^24 - 1 of them. The tape
+            // only holds one short record.
+            let select = [0x15, 0, 0, 0, 12, 0];
+            let mut data = [0; 12];
+            data[3] = 8;
+            data[9] = 1;
+            tape.data_out(&select, &data).unwrap();
+            tape.command(&REWIND).unwrap();
+
+            match tape.command(&[0x08, 1, 0xff, 0xff, 0xff, 0]) {
+                Ok(Transfer::In(buf)) => assert_eq!(0x10000, buf.len()),
+                _ => panic!("bad read response"),
+            }
+            assert_eq!(SENSE_ILI, tape.check().unwrap().flags);
+        });
+    }
+
+    #[test]
+    fn test_space() {
+        with_tape("space", |tape, _| {
+            for data in [b"a", b"b", b"c"] {
+                write(tape, data);
+                tape.command(&WRITE_MARK).unwrap();
+            }
+
+            // Back over two filemarks leaves the head before the second.
+            tape.command(&[0x11, 1, 0xff, 0xff, 0xfe, 0]).unwrap();
+            tape.command(&[0x11, 1, 0, 0, 1, 0]).unwrap();
+            assert_eq!(Ok(Transfer::In(b"c".to_vec())), read(tape, 1));
+
+            tape.command(&REWIND).unwrap();
+            tape.command(&[0x11, 0, 0, 0, 1, 0]).unwrap();
+            let sense = tape.command(&[0x11, 0, 0, 0, 2, 0]).unwrap_err();
+            assert_eq!(SENSE_FILEMARK, sense.flags);
+            assert_eq!(Some(2), sense.info);
+
+            tape.command(&REWIND).unwrap();
+            let sense = tape.command(&[0x11, 0, 0xff, 0xff, 0xff, 0]).unwrap_err();
+            assert_eq!(SENSE_EOM, sense.flags);
+            assert_eq!(ASCQ_BEGINNING_OF_MEDIUM, sense.ascq);
+        });
+    }
+
+    #[test]
+    fn test_write_protect() {
+        with_tape("protect", |tape, path| {
+            write(tape, b"data");
+            let mut tape = Tape::open(path.to_str().unwrap(), true, false).unwrap();
+            let sense = tape.command(&[0x0a, 0, 0, 0, 4, 0]).unwrap_err();
+            assert_eq!(SENSE_DATA_PROTECT, sense.key);
+            let sense = tape.command(&WRITE_MARK).unwrap_err();
+            assert_eq!(SENSE_DATA_PROTECT, sense.key);
+            assert_eq!(Ok(Transfer::In(b"data".to_vec())), read(&mut tape, 4));
+        });
+    }
+}
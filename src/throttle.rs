@@ -0,0 +1,135 @@
+//! Real-time pacing of the emulated CPU
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::cpu;
+
+use std::time::{Duration, Instant};
+
+// The throttle runs the CPU in short slices of emulated time and,
+// after each one, works out how far the guest has got ahead of the
+// wall clock. The main loop sleeps off the difference, so that over
+// any stretch longer than a slice the guest sees a 10 MHz 68010, or
+// the chosen fraction of one.
+//
+// If the host can't keep up, the guest falls behind. Past MAX_LAG
+// the throttle stops trying to catch up and starts counting again
+// from the present, rather than running flat out until the lost time
+// is made up.
+
+/// The emulated time run between looks at the clock, in cycles
+pub const SLICE: u64 = cpu::CLOCK_HZ / 100;
+
+/// How far behind the wall clock the guest may fall before the
+/// throttle gives up the lost time
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// How fast to run the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Speed {
+    /// A percentage of the real 4404's speed
+    Percent(u32),
+    /// As fast as the host allows
+    Turbo,
+}
+
+pub struct Throttle {
+    speed: Speed,
+    /// When the throttle started counting
+    start: Instant,
+    /// Cycles run since `start`
+    cycles: u64,
+}
+
+impl Throttle {
+    pub fn new(speed: Speed) -> Self {
+        Throttle {
+            speed,
+            start: Instant::now(),
+            cycles: 0,
+        }
+    }
+
+    /// Account for `ran` cycles of execution, and return how long to
+    /// sleep for the guest to be back in step with the wall clock at
+    /// `now`.
+    pub fn pace(&mut self, ran: u64, now: Instant) -> Duration {
+        let percent = match self.speed {
+            Speed::Percent(percent) => percent as u128,
+            Speed::Turbo => return Duration::ZERO,
+        };
+
+        self.cycles += ran;
+        let nanos = self.cycles as u128 * 1_000_000_000 * 100 / (cpu::CLOCK_HZ as u128 * percent);
+        let due = self.start + Duration::from_nanos(nanos as u64);
+
+        if due > now {
+            due - now
+        } else {
+            if now - due > MAX_LAG {
+                self.start = now;
+                self.cycles = 0;
+            }
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pace() {
+        let mut throttle = Throttle::new(Speed::Percent(100));
+        let start = throttle.start;
+
+        // 10ms of emulated time, run in 4ms
+        let delay = throttle.pace(SLICE, start + Duration::from_millis(4));
+        assert_eq!(Duration::from_millis(6), delay);
+
+        // Half speed takes twice as long.
+        let mut throttle = Throttle::new(Speed::Percent(50));
+        let start = throttle.start;
+        let delay = throttle.pace(SLICE, start + Duration::from_millis(4));
+        assert_eq!(Duration::from_millis(16), delay);
+
+        let mut throttle = Throttle::new(Speed::Turbo);
+        let start = throttle.start;
+        assert_eq!(Duration::ZERO, throttle.pace(SLICE, start));
+    }
+
+    #[test]
+    fn test_lag_is_forgiven() {
+        let mut throttle = Throttle::new(Speed::Percent(100));
+        let start = throttle.start;
+
+        // A slice that took a second leaves the guest 990ms behind.
+        // The throttle starts again from there rather than letting
+        // the next slices run unpaced.
+        let late = start + Duration::from_secs(1);
+        assert_eq!(Duration::ZERO, throttle.pace(SLICE, late));
+        let delay = throttle.pace(SLICE, late + Duration::from_millis(1));
+        assert_eq!(Duration::from_millis(9), delay);
+    }
+}
@@ -0,0 +1,236 @@
+//! Decoded SCSI bus trace
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::disk::rw_params;
+use crate::err::SimError;
+use crate::scsi::*;
+
+use byteorder::{BigEndian, ByteOrder};
+use log::error;
+use num_traits::FromPrimitive;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::time::Instant;
+
+// The trace has one line per transaction, from selection to bus
+// free, for example:
+//
+//   12.034561 ID0 READ(10)           lba=1234 blocks=8 in=4096 GOOD 1.412ms
+//   12.041207 ID0 READ(6)            lba=99999 blocks=1 CHECK ILLEGAL REQUEST asc=21 ascq=00 153.000µs
+//   12.050110 ID3 - SELECTION TIMEOUT 1.678s
+//
+// The first column is seconds since the trace was opened.
+
+/// A transaction in progress on the bus
+pub struct Transaction {
+    pub id: usize,
+    pub start: Instant,
+    pub cdb: Vec<u8>,
+    /// True if the target is a sequential access device
+    pub sequential: bool,
+    /// Bytes sent to the initiator in the Data In phase
+    pub data_in: usize,
+    /// Bytes expected from the initiator in the Data Out phase
+    pub data_out: usize,
+    pub status: Option<u8>,
+    pub sense: Option<Sense>,
+}
+
+impl Transaction {
+    pub fn new(id: usize) -> Self {
+        Transaction {
+            id,
+            start: Instant::now(),
+            cdb: Vec::new(),
+            sequential: false,
+            data_in: 0,
+            data_out: 0,
+            status: None,
+            sense: None,
+        }
+    }
+
+    /// The command and its decoded parameters
+    fn command(&self) -> String {
+        let cdb = &self.cdb;
+        let opcode = match cdb.first() {
+            Some(opcode) => *opcode,
+            None => return String::from("-"),
+        };
+
+        let op: Option<Op> = FromPrimitive::from_u8(opcode);
+        let name = match &op {
+            Some(op) => op.name().to_string(),
+            None => format!("OPCODE {opcode:02x}"),
+        };
+
+        let args = match op {
+            Some(Op::Read6) | Some(Op::Write6) if self.sequential => {
+                let count = BigEndian::read_u24(&cdb[2..5]);
+                if cdb[1] & 1 != 0 {
+                    format!("blocks={count}")
+                } else {
+                    format!("length={count}")
+                }
+            }
+            Some(Op::Read6) | Some(Op::Write6) | Some(Op::Read10) | Some(Op::Write10) => {
+                let (lba, count) = rw_params(cdb);
+                format!("lba={lba} blocks={count}")
+            }
+            Some(Op::Space) => {
+                let count = ((BigEndian::read_u24(&cdb[2..5]) << 8) as i32) >> 8;
+                format!("code={} count={}", cdb[1] & 7, count)
+            }
+            Some(Op::WriteFileMark) => format!("count={}", BigEndian::read_u24(&cdb[2..5])),
+            Some(Op::Inquiry)
+            | Some(Op::RequestSense)
+            | Some(Op::ModeSense6)
+            | Some(Op::ModeSelect6) => {
+                format!("length={}", cdb[4])
+            }
+            _ => String::new(),
+        };
+
+        format!("{name:<18} {args}")
+    }
+
+    /// The decoded line for a finished transaction
+    pub fn describe(&self, since: Instant, outcome: Option<&str>) -> String {
+        let mut line = format!(
+            "{:.6} ID{} {}",
+            self.start.duration_since(since).as_secs_f64(),
+            self.id,
+            self.command().trim_end()
+        );
+
+        if self.data_in > 0 {
+            line.push_str(&format!(" in={}", self.data_in));
+        }
+        if self.data_out > 0 {
+            line.push_str(&format!(" out={}", self.data_out));
+        }
+
+        match self.status {
+            Some(STATUS_GOOD) => line.push_str(" GOOD"),
+            Some(STATUS_CHECK) => line.push_str(" CHECK"),
+            Some(STATUS_BUSY) => line.push_str(" BUSY"),
+            Some(status) => line.push_str(&format!(" STATUS {status:02x}")),
+            None => {}
+        }
+
+        if let Some(sense) = &self.sense {
+            line.push_str(&format!(
+                " {} asc={:02x} ascq={:02x}",
+                sense_key_name(sense.key),
+                sense.asc,
+                sense.ascq
+            ));
+            if let Some(info) = sense.info {
+                line.push_str(&format!(" info={info}"));
+            }
+        }
+
+        if let Some(outcome) = outcome {
+            line.push(' ');
+            line.push_str(outcome);
+        }
+
+        line.push_str(&format!(" {:.3?}", self.start.elapsed()));
+        line
+    }
+}
+
+fn sense_key_name(key: u8) -> &'static str {
+    match key {
+        0x0 => "NO SENSE",
+        0x1 => "RECOVERED ERROR",
+        0x2 => "NOT READY",
+        0x3 => "MEDIUM ERROR",
+        0x4 => "HARDWARE ERROR",
+        0x5 => "ILLEGAL REQUEST",
+        0x6 => "UNIT ATTENTION",
+        0x7 => "DATA PROTECT",
+        0x8 => "BLANK CHECK",
+        0xb => "ABORTED COMMAND",
+        _ => "SENSE",
+    }
+}
+
+/// A trace file, written as transactions finish
+pub struct Trace {
+    out: LineWriter<File>,
+    start: Instant,
+}
+
+impl Trace {
+    pub fn create(path: &str) -> Result<Trace, SimError> {
+        let file = File::create(path).map_err(|e| SimError::Init(format!("{path}: {e}")))?;
+        Ok(Trace {
+            out: LineWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, txn: &Transaction, outcome: Option<&str>) {
+        let line = txn.describe(self.start, outcome);
+        if let Err(e) = writeln!(self.out, "{line}") {
+            error!("Unable to write SCSI trace: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let mut txn = Transaction::new(0);
+        txn.cdb = vec![0x28, 0, 0, 0, 0x04, 0xd2, 0, 0, 8, 0];
+        txn.data_in = 4096;
+        txn.status = Some(STATUS_GOOD);
+        let line = txn.describe(txn.start, None);
+        assert!(line.starts_with("0.000000 ID0 READ(10)"), "{line}");
+        assert!(line.contains("lba=1234 blocks=8 in=4096 GOOD"), "{line}");
+
+        let mut txn = Transaction::new(4);
+        txn.cdb = vec![0x08, 0x01, 0, 0, 2, 0];
+        txn.sequential = true;
+        txn.status = Some(STATUS_CHECK);
+        txn.sense = Some(Sense {
+            info: Some(1),
+            ..Sense::new(SENSE_BLANK_CHECK, 0)
+        });
+        let line = txn.describe(txn.start, None);
+        assert!(line.contains("READ(6)"), "{line}");
+        assert!(
+            line.contains("blocks=2 CHECK BLANK CHECK asc=00 ascq=00 info=1"),
+            "{line}"
+        );
+
+        let txn = Transaction::new(3);
+        let line = txn.describe(txn.start, Some("SELECTION TIMEOUT"));
+        assert!(line.contains("ID3 - SELECTION TIMEOUT"), "{line}");
+    }
+}
@@ -0,0 +1,816 @@
+//! Uniflex filesystem access for disk images
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+// This module is shared by the emulator and the tek4404-fs tool, so
+// it stands on its own and does not use anything else in the crate.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A Uniflex filesystem is made of 512 byte blocks, and all values
+// are big-endian. Block addresses are 24 bits wide.
+//
+//   Block 0:     Boot block, not used by the filesystem
+//   Block 1:     System Information Record (SIR)
+//   Block 2+:    File Descriptor Nodes (FDNs), eight per block
+//   The rest:    File data, and the free block list
+//
+// System Information Record:
+//
+//   0x10: Number of FDN blocks (16 bits)
+//   0x12: Size of the volume in blocks (24 bits)
+//   0x15: Number of free blocks (24 bits)
+//   0x18: Number of free FDNs (16 bits)
+//   0x1a: Filesystem name (14 bytes)
+//   0x28: Pack name (14 bytes)
+//   0x50: Free block cache: a count (16 bits), then up to 50 block
+//         addresses. The first address in the cache is the block
+//         holding the next cache full of free blocks, in the same
+//         format, or 0 at the end of the list.
+//
+// File Descriptor Node (64 bytes, numbered from 1):
+//
+//   0x00: Mode (allocated, directory, block or character special)
+//   0x01: Permissions
+//   0x02: Link count
+//   0x03: Owner ID (16 bits)
+//   0x05: Size in bytes (32 bits)
+//   0x09: Block map: 13 block addresses. The first ten map data
+//         blocks directly, then one single, one double, and one
+//         triple indirect block. An indirect block holds 128 block
+//         addresses, and the rest of it is unused.
+//   0x30: Modification time, in seconds since 1980 (32 bits)
+//
+// A directory is a file of 16 byte entries: an FDN number (16 bits)
+// and a name of up to 14 bytes, padded with NULs. An FDN number of 0
+// marks an unused entry. FDN 1 is the root directory.
+//
+// The FDN location and size, the file size, the block map up to the
+// double indirect block, and the directory format are all as the boot
+// ROM's loader reads them to find system.boot (0x746b48-0x747578 in
+// rom/boot.bin). The loader never reads the SIR, or an FDN's mode,
+// permissions, owner or time, so those offsets are provisional: they
+// have not been checked against a real 4404 volume.
+
+pub const BLOCK_SIZE: usize = 512;
+pub const ROOT_FDN: u16 = 1;
+
+const SIR_BLOCK: u32 = 1;
+const FDN_START: u32 = 2;
+const FDN_SIZE: usize = 64;
+const FDNS_PER_BLOCK: u16 = (BLOCK_SIZE / FDN_SIZE) as u16;
+
+const SIR_FDN_BLOCKS: usize = 0x10;
+const SIR_VOLUME_SIZE: usize = 0x12;
+const SIR_FREE_BLOCKS: usize = 0x15;
+const SIR_FREE_FDNS: usize = 0x18;
+const SIR_FS_NAME: usize = 0x1a;
+const SIR_PACK_NAME: usize = 0x28;
+const SIR_FREE_CACHE: usize = 0x50;
+
+/// Entries in the free block cache
+const FREE_CACHE_LEN: usize = 50;
+
+const DIRECT_BLOCKS: u32 = 10;
+const PER_INDIRECT: u32 = 128;
+
+const DIR_ENTRY_SIZE: usize = 16;
+const NAME_LEN: usize = 14;
+
+/// Seconds from the Unix epoch to the Uniflex epoch
+const EPOCH_OFFSET: u64 = 315532800;
+
+//
+// FDN Mode Bits
+//
+pub const MODE_ALLOCATED: u8 = 0x01;
+pub const MODE_BLOCK: u8 = 0x02;
+pub const MODE_CHAR: u8 = 0x04;
+pub const MODE_DIR: u8 = 0x08;
+
+//
+// FDN Permission Bits
+//
+const PERM_OWNER_READ: u8 = 0x01;
+const PERM_OWNER_WRITE: u8 = 0x02;
+const PERM_OWNER_EXEC: u8 = 0x04;
+const PERM_OTHER_READ: u8 = 0x08;
+const PERM_OTHER_WRITE: u8 = 0x10;
+const PERM_OTHER_EXEC: u8 = 0x20;
+
+const PERM_FILE: u8 = PERM_OWNER_READ | PERM_OWNER_WRITE | PERM_OTHER_READ;
+const PERM_DIR: u8 = PERM_FILE | PERM_OWNER_EXEC | PERM_OTHER_EXEC;
+
+pub enum FsError {
+    Io(io::Error),
+    Corrupt(String),
+    NotFound(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    NoSpace,
+    NoFdns,
+}
+
+impl fmt::Debug for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Io(e) => write!(f, "I/O Error: {e}"),
+            FsError::Corrupt(s) => write!(f, "Corrupt Filesystem: {s}"),
+            FsError::NotFound(s) => write!(f, "{s}: No such file or directory"),
+            FsError::NotADirectory(s) => write!(f, "{s}: Not a directory"),
+            FsError::IsADirectory(s) => write!(f, "{s}: Is a directory"),
+            FsError::NoSpace => write!(f, "No free blocks"),
+            FsError::NoFdns => write!(f, "No free FDNs"),
+        }
+    }
+}
+
+impl Error for FsError {}
+
+impl From<io::Error> for FsError {
+    fn from(e: io::Error) -> Self {
+        FsError::Io(e)
+    }
+}
+
+/// A File Descriptor Node
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fdn {
+    pub mode: u8,
+    pub perm: u8,
+    pub links: u8,
+    pub owner: u16,
+    pub size: u32,
+    pub blocks: [u32; 13],
+    pub time: u32,
+}
+
+impl Fdn {
+    fn from_bytes(buf: &[u8]) -> Self {
+        let mut fdn = Fdn {
+            mode: buf[0],
+            perm: buf[1],
+            links: buf[2],
+            owner: BigEndian::read_u16(&buf[3..5]),
+            size: BigEndian::read_u32(&buf[5..9]),
+            time: BigEndian::read_u32(&buf[0x30..0x34]),
+            ..Default::default()
+        };
+        for (i, b) in fdn.blocks.iter_mut().enumerate() {
+            *b = BigEndian::read_u24(&buf[9 + i * 3..]);
+        }
+        fdn
+    }
+
+    fn to_bytes(&self, buf: &mut [u8]) {
+        buf.fill(0);
+        buf[0] = self.mode;
+        buf[1] = self.perm;
+        buf[2] = self.links;
+        BigEndian::write_u16(&mut buf[3..5], self.owner);
+        BigEndian::write_u32(&mut buf[5..9], self.size);
+        for (i, b) in self.blocks.iter().enumerate() {
+            BigEndian::write_u24(&mut buf[9 + i * 3..], *b);
+        }
+        BigEndian::write_u32(&mut buf[0x30..0x34], self.time);
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & MODE_DIR != 0
+    }
+
+    /// The mode and permissions, in the style of `ls -l`
+    pub fn mode_string(&self) -> String {
+        let kind = if self.is_dir() {
+            'd'
+        } else if self.mode & MODE_BLOCK != 0 {
+            'b'
+        } else if self.mode & MODE_CHAR != 0 {
+            'c'
+        } else {
+            '-'
+        };
+        let bit = |mask: u8, c: char| if self.perm & mask != 0 { c } else { '-' };
+        [
+            kind,
+            bit(PERM_OWNER_READ, 'r'),
+            bit(PERM_OWNER_WRITE, 'w'),
+            bit(PERM_OWNER_EXEC, 'x'),
+            bit(PERM_OTHER_READ, 'r'),
+            bit(PERM_OTHER_WRITE, 'w'),
+            bit(PERM_OTHER_EXEC, 'x'),
+        ]
+        .iter()
+        .collect()
+    }
+}
+
+/// An entry in a directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub fdn: u16,
+    pub name: String,
+}
+
+/// The current time, in Uniflex form
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().saturating_sub(EPOCH_OFFSET) as u32)
+        .unwrap_or(0)
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+/// A Uniflex filesystem on a disk image.
+pub struct Filesystem {
+    file: File,
+    sir: Vec<u8>,
+}
+
+impl Filesystem {
+    /// Open the filesystem in the image at `path`.
+    pub fn open(path: &str, writable: bool) -> Result<Filesystem, FsError> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let mut fs = Filesystem {
+            file,
+            sir: vec![0; BLOCK_SIZE],
+        };
+        fs.sir = fs.read_block(SIR_BLOCK)?;
+
+        let blocks = fs.file.metadata()?.len() / BLOCK_SIZE as u64;
+        let size = fs.volume_size() as u64;
+        if size == 0 || size > blocks || fs.fdn_blocks() as u64 + FDN_START as u64 > size {
+            return Err(FsError::Corrupt(format!(
+                "{path}: no Uniflex filesystem found"
+            )));
+        }
+
+        Ok(fs)
+    }
+
+    /// Write an empty filesystem of `blocks` blocks to the image at
+    /// `path`, with one FDN for every eight blocks. The image must
+    /// already be at least that large. Block addresses are 24 bits,
+    /// so a larger image is only partly used.
+    pub fn format(path: &str, blocks: u32, name: &str) -> Result<Filesystem, FsError> {
+        let blocks = blocks.min(0xffffff);
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let fdn_blocks = (blocks / 64).clamp(1, 0xffff / FDNS_PER_BLOCK as u32);
+        let data_start = FDN_START + fdn_blocks;
+        if blocks < data_start + 2 {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut fs = Filesystem {
+            file,
+            sir: vec![0; BLOCK_SIZE],
+        };
+
+        let fdns = fdn_blocks as u16 * FDNS_PER_BLOCK;
+        BigEndian::write_u16(&mut fs.sir[SIR_FDN_BLOCKS..], fdn_blocks as u16);
+        BigEndian::write_u24(&mut fs.sir[SIR_VOLUME_SIZE..], blocks);
+        BigEndian::write_u16(&mut fs.sir[SIR_FREE_FDNS..], fdns);
+        let name = &name.as_bytes()[..name.len().min(NAME_LEN)];
+        fs.sir[SIR_FS_NAME..SIR_FS_NAME + name.len()].copy_from_slice(name);
+        fs.sir[SIR_PACK_NAME..SIR_PACK_NAME + name.len()].copy_from_slice(name);
+
+        let zero = vec![0; BLOCK_SIZE];
+        for block in FDN_START..data_start {
+            fs.write_block(block, &zero)?;
+        }
+
+        // A cache holding only the end of list marker, then every
+        // data block, freed from the top down so that allocation
+        // starts at the bottom of the disk.
+        BigEndian::write_u16(&mut fs.sir[SIR_FREE_CACHE..], 1);
+        for block in (data_start..blocks).rev() {
+            fs.free_block(block)?;
+        }
+
+        let root = fs.alloc_fdn(MODE_ALLOCATED | MODE_DIR, PERM_DIR)?;
+        fs.add_entry(root, ".", root)?;
+        fs.add_entry(root, "..", root)?;
+        let mut fdn = fs.read_fdn(root)?;
+        fdn.links = 2;
+        fs.write_fdn(root, &fdn)?;
+        fs.sync()?;
+
+        Ok(fs)
+    }
+
+    /// Write the SIR back to the image.
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        let sir = self.sir.clone();
+        self.write_block(SIR_BLOCK, &sir)
+    }
+
+    pub fn volume_size(&self) -> u32 {
+        BigEndian::read_u24(&self.sir[SIR_VOLUME_SIZE..])
+    }
+
+    pub fn free_blocks(&self) -> u32 {
+        BigEndian::read_u24(&self.sir[SIR_FREE_BLOCKS..])
+    }
+
+    fn fdn_blocks(&self) -> u32 {
+        BigEndian::read_u16(&self.sir[SIR_FDN_BLOCKS..]) as u32
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Vec<u8>, FsError> {
+        let mut buf = vec![0; BLOCK_SIZE];
+        self.file
+            .seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8]) -> Result<(), FsError> {
+        self.file
+            .seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+
+    //
+    // Free block list
+    //
+
+    fn cache_len(&self) -> usize {
+        BigEndian::read_u16(&self.sir[SIR_FREE_CACHE..]) as usize
+    }
+
+    fn cache_entry(&mut self, index: usize) -> &mut [u8] {
+        let offset = SIR_FREE_CACHE + 2 + index * 3;
+        &mut self.sir[offset..offset + 3]
+    }
+
+    fn set_free_blocks(&mut self, count: u32) {
+        BigEndian::write_u24(&mut self.sir[SIR_FREE_BLOCKS..], count);
+    }
+
+    fn alloc_block(&mut self) -> Result<u32, FsError> {
+        let n = self.cache_len();
+        if n == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let block = BigEndian::read_u24(self.cache_entry(n - 1));
+        if block == 0 {
+            return Err(FsError::NoSpace);
+        }
+
+        if n == 1 {
+            // The last entry links to the next cache full.
+            let next = self.read_block(block)?;
+            let len = BigEndian::read_u16(&next[0..2]) as usize;
+            if len > FREE_CACHE_LEN {
+                return Err(FsError::Corrupt(format!("free list block {block}")));
+            }
+            let cache = SIR_FREE_CACHE..SIR_FREE_CACHE + 2 + FREE_CACHE_LEN * 3;
+            self.sir[cache].copy_from_slice(&next[..2 + FREE_CACHE_LEN * 3]);
+        } else {
+            BigEndian::write_u16(&mut self.sir[SIR_FREE_CACHE..], (n - 1) as u16);
+        }
+
+        self.set_free_blocks(self.free_blocks().saturating_sub(1));
+        self.write_block(block, &[0; BLOCK_SIZE])?;
+        Ok(block)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), FsError> {
+        let mut n = self.cache_len();
+        if n == FREE_CACHE_LEN {
+            // Spill the full cache into the block being freed, which
+            // becomes the link to it.
+            let mut buf = vec![0; BLOCK_SIZE];
+            let cache = SIR_FREE_CACHE..SIR_FREE_CACHE + 2 + FREE_CACHE_LEN * 3;
+            buf[..2 + FREE_CACHE_LEN * 3].copy_from_slice(&self.sir[cache]);
+            self.write_block(block, &buf)?;
+            n = 0;
+        }
+        BigEndian::write_u24(self.cache_entry(n), block);
+        BigEndian::write_u16(&mut self.sir[SIR_FREE_CACHE..], (n + 1) as u16);
+        self.set_free_blocks(self.free_blocks() + 1);
+        Ok(())
+    }
+
+    //
+    // FDNs
+    //
+
+    fn fdn_location(&self, n: u16) -> Result<(u32, usize), FsError> {
+        let index = n.wrapping_sub(1);
+        let block = FDN_START + (index / FDNS_PER_BLOCK) as u32;
+        if n == 0 || block >= FDN_START + self.fdn_blocks() {
+            return Err(FsError::Corrupt(format!("bad FDN number {n}")));
+        }
+        Ok((block, (index % FDNS_PER_BLOCK) as usize * FDN_SIZE))
+    }
+
+    pub fn read_fdn(&mut self, n: u16) -> Result<Fdn, FsError> {
+        let (block, offset) = self.fdn_location(n)?;
+        let buf = self.read_block(block)?;
+        Ok(Fdn::from_bytes(&buf[offset..offset + FDN_SIZE]))
+    }
+
+    fn write_fdn(&mut self, n: u16, fdn: &Fdn) -> Result<(), FsError> {
+        let (block, offset) = self.fdn_location(n)?;
+        let mut buf = self.read_block(block)?;
+        fdn.to_bytes(&mut buf[offset..offset + FDN_SIZE]);
+        self.write_block(block, &buf)
+    }
+
+    fn alloc_fdn(&mut self, mode: u8, perm: u8) -> Result<u16, FsError> {
+        for block in 0..self.fdn_blocks() {
+            let buf = self.read_block(FDN_START + block)?;
+            let free = buf
+                .chunks(FDN_SIZE)
+                .position(|f| f[0] & MODE_ALLOCATED == 0);
+            if let Some(i) = free {
+                let n = (block * FDNS_PER_BLOCK as u32 + i as u32 + 1) as u16;
+                let fdn = Fdn {
+                    mode,
+                    perm,
+                    links: 1,
+                    time: now(),
+                    ..Default::default()
+                };
+                self.write_fdn(n, &fdn)?;
+                let count = BigEndian::read_u16(&self.sir[SIR_FREE_FDNS..]);
+                BigEndian::write_u16(&mut self.sir[SIR_FREE_FDNS..], count.saturating_sub(1));
+                return Ok(n);
+            }
+        }
+        Err(FsError::NoFdns)
+    }
+
+    //
+    // File contents
+    //
+
+    /// The disk block holding block `index` of a file, or 0 if there
+    /// is none. With `alloc`, missing blocks are allocated.
+    fn map_block(&mut self, fdn: &mut Fdn, index: u32, alloc: bool) -> Result<u32, FsError> {
+        if index < DIRECT_BLOCKS {
+            let slot = &mut fdn.blocks[index as usize];
+            if *slot == 0 && alloc {
+                *slot = self.alloc_block()?;
+            }
+            return Ok(*slot);
+        }
+
+        // Find the indirect block covering this index, and how deep it
+        // goes.
+        let mut index = index - DIRECT_BLOCKS;
+        let mut depth = 1;
+        let mut span = PER_INDIRECT;
+        while index >= span {
+            index -= span;
+            depth += 1;
+            span *= PER_INDIRECT;
+            if depth > 3 {
+                return Err(FsError::NoSpace);
+            }
+        }
+
+        let top = &mut fdn.blocks[DIRECT_BLOCKS as usize + depth - 1];
+        if *top == 0 {
+            if !alloc {
+                return Ok(0);
+            }
+            *top = self.alloc_block()?;
+        }
+
+        let mut block = *top;
+        for _ in 0..depth {
+            span /= PER_INDIRECT;
+            let slot = (index / span) as usize * 3;
+            index %= span;
+
+            let mut buf = self.read_block(block)?;
+            let mut next = BigEndian::read_u24(&buf[slot..]);
+            if next == 0 {
+                if !alloc {
+                    return Ok(0);
+                }
+                next = self.alloc_block()?;
+                BigEndian::write_u24(&mut buf[slot..], next);
+                self.write_block(block, &buf)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Release a block and, for an indirect block, everything below it.
+    fn free_tree(&mut self, block: u32, depth: usize) -> Result<(), FsError> {
+        if block == 0 {
+            return Ok(());
+        }
+        if depth > 0 {
+            let buf = self.read_block(block)?;
+            for slot in buf.chunks_exact(3).take(PER_INDIRECT as usize) {
+                self.free_tree(BigEndian::read_u24(slot), depth - 1)?;
+            }
+        }
+        self.free_block(block)
+    }
+
+    fn truncate(&mut self, fdn: &mut Fdn) -> Result<(), FsError> {
+        for (i, block) in fdn.blocks.iter().enumerate() {
+            let depth = i.saturating_sub(DIRECT_BLOCKS as usize - 1);
+            self.free_tree(*block, depth)?;
+        }
+        fdn.blocks = [0; 13];
+        fdn.size = 0;
+        Ok(())
+    }
+
+    pub fn read_file(&mut self, fdn: &Fdn) -> Result<Vec<u8>, FsError> {
+        let mut fdn = fdn.clone();
+        let mut data = Vec::with_capacity(fdn.size as usize);
+        let count = (fdn.size as usize).div_ceil(BLOCK_SIZE) as u32;
+        for index in 0..count {
+            match self.map_block(&mut fdn, index, false)? {
+                0 => data.extend_from_slice(&[0; BLOCK_SIZE]),
+                block => data.extend_from_slice(&self.read_block(block)?),
+            }
+        }
+        data.truncate(fdn.size as usize);
+        Ok(data)
+    }
+
+    /// Write `data` at `offset` in a file, growing it as needed.
+    fn write_at(&mut self, fdn: &mut Fdn, offset: usize, data: &[u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done;
+            let start = pos % BLOCK_SIZE;
+            let len = (data.len() - done).min(BLOCK_SIZE - start);
+
+            let block = self.map_block(fdn, (pos / BLOCK_SIZE) as u32, true)?;
+            let mut buf = self.read_block(block)?;
+            buf[start..start + len].copy_from_slice(&data[done..done + len]);
+            self.write_block(block, &buf)?;
+            done += len;
+        }
+        fdn.size = fdn.size.max((offset + data.len()) as u32);
+        fdn.time = now();
+        Ok(())
+    }
+
+    //
+    // Directories
+    //
+
+    pub fn list(&mut self, dir: u16) -> Result<Vec<DirEntry>, FsError> {
+        let fdn = self.read_fdn(dir)?;
+        if !fdn.is_dir() {
+            return Err(FsError::NotADirectory(format!("FDN {dir}")));
+        }
+
+        let data = self.read_file(&fdn)?;
+        Ok(data
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .filter_map(|e| match BigEndian::read_u16(&e[0..2]) {
+                0 => None,
+                fdn => {
+                    let name = &e[2..];
+                    let len = name.iter().position(|c| *c == 0).unwrap_or(NAME_LEN);
+                    Some(DirEntry {
+                        fdn,
+                        name: String::from_utf8_lossy(&name[..len]).into_owned(),
+                    })
+                }
+            })
+            .collect())
+    }
+
+    fn add_entry(&mut self, dir: u16, name: &str, fdn: u16) -> Result<(), FsError> {
+        let mut dir_fdn = self.read_fdn(dir)?;
+        let data = self.read_file(&dir_fdn)?;
+        let offset = data
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .position(|e| BigEndian::read_u16(&e[0..2]) == 0)
+            .map(|i| i * DIR_ENTRY_SIZE)
+            .unwrap_or(data.len());
+
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        BigEndian::write_u16(&mut entry[0..2], fdn);
+        let name = &name.as_bytes()[..name.len().min(NAME_LEN)];
+        entry[2..2 + name.len()].copy_from_slice(name);
+
+        self.write_at(&mut dir_fdn, offset, &entry)?;
+        self.write_fdn(dir, &dir_fdn)
+    }
+
+    /// Find the FDN number of the file at `path`.
+    pub fn lookup(&mut self, path: &str) -> Result<u16, FsError> {
+        let mut fdn = ROOT_FDN;
+        for name in split_path(path) {
+            fdn = self
+                .list(fdn)
+                .map_err(|_| FsError::NotADirectory(path.to_string()))?
+                .into_iter()
+                .find(|e| e.name == name)
+                .map(|e| e.fdn)
+                .ok_or_else(|| FsError::NotFound(path.to_string()))?;
+        }
+        Ok(fdn)
+    }
+
+    /// The FDN of the file at `path`.
+    pub fn stat(&mut self, path: &str) -> Result<Fdn, FsError> {
+        let n = self.lookup(path)?;
+        self.read_fdn(n)
+    }
+
+    /// Write `data` to the file at `path`, replacing its contents if
+    /// it exists and creating it if it does not. The directory it
+    /// goes in must already exist.
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let n = match self.lookup(path) {
+            Ok(n) => {
+                let mut fdn = self.read_fdn(n)?;
+                if fdn.is_dir() {
+                    return Err(FsError::IsADirectory(path.to_string()));
+                }
+                self.truncate(&mut fdn)?;
+                self.write_fdn(n, &fdn)?;
+                n
+            }
+            Err(FsError::NotFound(_)) => {
+                let mut components = split_path(path);
+                let name = components
+                    .pop()
+                    .ok_or_else(|| FsError::IsADirectory(path.to_string()))?;
+                let dir = self.lookup(&components.join("/"))?;
+                if !self.read_fdn(dir)?.is_dir() {
+                    return Err(FsError::NotADirectory(path.to_string()));
+                }
+                let n = self.alloc_fdn(MODE_ALLOCATED, PERM_FILE)?;
+                self.add_entry(dir, name, n)?;
+                n
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut fdn = self.read_fdn(n)?;
+        self.write_at(&mut fdn, 0, data)?;
+        self.write_fdn(n, &fdn)?;
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_fs<T>(name: &str, blocks: u32, test: T)
+    where
+        T: FnOnce(&mut Filesystem, &str),
+    {
+        let path = std::env::temp_dir().join(format!("tek4404-{name}.img"));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, vec![0; blocks as usize * BLOCK_SIZE]).unwrap();
+        let mut fs = Filesystem::format(path, blocks, "test").unwrap();
+        test(&mut fs, path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_format() {
+        with_fs("mkfs", 1000, |fs, path| {
+            // Root directory is one block, out of 1000 less the boot
+            // block, SIR, and 15 blocks of FDNs.
+            assert_eq!(1000 - 18, fs.free_blocks());
+            let names: Vec<String> = fs
+                .list(ROOT_FDN)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.name)
+                .collect();
+            assert_eq!(vec![".", ".."], names);
+            assert!(Filesystem::open(path, false).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_write_and_read_back() {
+        with_fs("inject", 4000, |fs, path| {
+            // Large enough to need a double indirect block
+            let data: Vec<u8> = (0..200 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+            fs.write_file("/big", &data).unwrap();
+            fs.write_file("small", b"hello").unwrap();
+
+            let mut fs = Filesystem::open(path, false).unwrap();
+            let fdn = fs.stat("/big").unwrap();
+            assert_eq!(data, fs.read_file(&fdn).unwrap());
+            let fdn = fs.stat("/small").unwrap();
+            assert_eq!(b"hello".to_vec(), fs.read_file(&fdn).unwrap());
+        });
+    }
+
+    /// Read a file from the root directory of an image the way the
+    /// boot ROM's loader does, straight from the image's bytes.
+    fn rom_load(image: &[u8], name: &str) -> Vec<u8> {
+        let block = |n: usize| &image[n * BLOCK_SIZE..(n + 1) * BLOCK_SIZE];
+        let addr = |buf: &[u8], slot: usize| BigEndian::read_u24(&buf[slot * 3..]) as usize;
+        let fdn = |n: usize| &block((n - 1) / 8 + 2)[(n - 1) % 8 * 64..][..64];
+        let file = |fdn: &[u8]| {
+            let size = BigEndian::read_u32(&fdn[5..]) as usize;
+            let mut data = Vec::new();
+            for i in 0..size.div_ceil(BLOCK_SIZE) {
+                let n = match i {
+                    0..=9 => addr(&fdn[9..], i),
+                    10..=137 => addr(block(addr(&fdn[9..], 10)), i - 10),
+                    _ => {
+                        let single = addr(block(addr(&fdn[9..], 11)), (i - 138) / 128);
+                        addr(block(single), (i - 138) % 128)
+                    }
+                };
+                data.extend_from_slice(block(n));
+            }
+            data.truncate(size);
+            data
+        };
+
+        let root = file(fdn(ROOT_FDN as usize));
+        let entry = root
+            .chunks_exact(16)
+            .find(|e| e[2..].split(|c| *c == 0).next() == Some(name.as_bytes()))
+            .unwrap();
+        file(fdn(BigEndian::read_u16(entry) as usize))
+    }
+
+    #[test]
+    fn test_boot_rom_layout() {
+        with_fs("rom", 4000, |fs, path| {
+            // Large enough to need a double indirect block
+            let data: Vec<u8> = (0..200 * BLOCK_SIZE + 7).map(|i| (i % 251) as u8).collect();
+            fs.write_file("/system.boot", &data).unwrap();
+            fs.sync().unwrap();
+
+            let image = std::fs::read(path).unwrap();
+            assert_eq!(data, rom_load(&image, "system.boot"));
+        });
+    }
+
+    #[test]
+    fn test_replace_frees_blocks() {
+        with_fs("replace", 1000, |fs, _| {
+            let free = fs.free_blocks();
+            fs.write_file("/f", &[1; 20 * BLOCK_SIZE]).unwrap();
+            // Twenty data blocks and one indirect block
+            assert_eq!(free - 21, fs.free_blocks());
+            fs.write_file("/f", &[2; BLOCK_SIZE]).unwrap();
+            assert_eq!(free - 1, fs.free_blocks());
+        });
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        with_fs("lookup", 1000, |fs, _| {
+            fs.write_file("/f", b"x").unwrap();
+            assert!(matches!(fs.lookup("/nope"), Err(FsError::NotFound(_))));
+            assert!(matches!(fs.lookup("/f/x"), Err(FsError::NotADirectory(_))));
+            assert!(matches!(
+                fs.write_file("/d/f", b""),
+                Err(FsError::NotFound(_))
+            ));
+        });
+    }
+}
@@ -24,46 +24,337 @@
 //
 use crate::bus::*;
 use crate::err::*;
+use crate::irq::{self, Interrupt};
+use crate::service::ServiceKey;
+use crate::vram::{VideoRam, LINES, LINE_BYTES};
 
-use log::debug;
+use log::{debug, info};
+use std::ops::Range;
 use std::result::Result;
+use std::time::Duration;
 
-pub struct Video {}
+// The video controller has two registers, each repeated through its
+// half of the block:
+//
+//   0x782000-0x783fff  Control (8 bits)
+//                        bit 7: retrace pending (write 1 to clear)
+//                        bit 6: retrace interrupt enable
+//                        bit 5: display enable
+//                        bit 4: inverse video
+//   0x784000-0x785fff  Pan (16 bits): the offset into video RAM, in
+//                      16-bit words, of the top left corner of the
+//                      display
+//
+// This layout is provisional. It is not taken from Tektronix
+// documentation or schematics, and the control bits and the pan
+// register's address are a best guess at what the boot ROM and
+// Uniflex expect. Expect it to change once a source turns up.
+//
+// The 640x480 display is a window onto the 1024x1024 framebuffer.
+// Video RAM is 64 words to a line, so the pan offset moves the
+// window down by whole lines and across by 16 pixels at a time. A
+// window running off the end of video RAM wraps to the start.
+//
+// Vertical retrace comes round 60 times a second of emulated time.
+// Each one sets the pending bit, which requests a level 6 interrupt
+// while retrace interrupts are enabled.
+//
+// The screen keeps the picture last drawn, and redraws only the rows
+// showing lines of video RAM written since, unless the control or pan
+// registers have changed. While the guest leaves the display alone,
+// updating it costs nothing.
+
+/// Visible display width, in pixels
+pub const DISPLAY_WIDTH: usize = 640;
+/// Visible display height, in pixels
+pub const DISPLAY_HEIGHT: usize = 480;
+
+const PAN_REGISTER: usize = 0x784000;
+
+/// The time from one vertical retrace to the next
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+pub const CTRL_RETRACE: u8 = 0x80;
+pub const CTRL_RETRACE_ENABLE: u8 = 0x40;
+pub const CTRL_ENABLE: u8 = 0x20;
+pub const CTRL_INVERSE: u8 = 0x10;
+
+/// RGB332 black and white
+const BLACK: u8 = 0;
+const WHITE: u8 = 0xff;
+
+pub struct Video {
+    /// The control register, but for the retrace pending bit
+    pub control: u8,
+    pub pan: u16,
+    pub retrace: bool,
+}
 
 impl Video {
     pub fn new() -> Self {
-        Video {}
+        Video {
+            control: CTRL_ENABLE,
+            pan: 0,
+            retrace: false,
+        }
+    }
+
+    /// Begin generating vertical retraces. This is kept out of new()
+    /// so that a Video can be built without scheduling anything.
+    pub fn start(&mut self) {
+        schedule!(ServiceKey::Video, FRAME);
+    }
+
+    fn read_control(&self) -> u8 {
+        if self.retrace {
+            self.control | CTRL_RETRACE
+        } else {
+            self.control
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        if (self.control ^ value) & CTRL_ENABLE != 0 {
+            let state = if value & CTRL_ENABLE != 0 {
+                "on"
+            } else {
+                "off"
+            };
+            info!("Display {}", state);
+        }
+        if value & CTRL_RETRACE != 0 {
+            self.retrace = false;
+        }
+        self.control = value & !CTRL_RETRACE;
+        self.update_irq();
+    }
+
+    fn update_irq(&self) {
+        irq::set(
+            Interrupt::Vsync,
+            self.retrace && self.control & CTRL_RETRACE_ENABLE != 0,
+        );
+    }
+
+    /// The framebuffer lines that display row `row` is drawn from.
+    /// A row panned part way across a line runs on into the next.
+    fn lines(&self, row: usize) -> (usize, usize) {
+        let start = self.pan as usize * 2 + row * LINE_BYTES;
+        let end = start + DISPLAY_WIDTH / 8 - 1;
+        (start / LINE_BYTES % LINES, end / LINE_BYTES % LINES)
+    }
+
+    /// Render display row `row` from `vram` to `out`, one RGB332 byte
+    /// per pixel. Set bits are black, unless the display is inverted.
+    fn render_row(&self, vram: &[u8], row: usize, out: &mut [u8]) {
+        if self.control & CTRL_ENABLE == 0 {
+            out.fill(BLACK);
+            return;
+        }
+
+        let (set, clear) = if self.control & CTRL_INVERSE == 0 {
+            (BLACK, WHITE)
+        } else {
+            (WHITE, BLACK)
+        };
+
+        let start = self.pan as usize * 2 + row * LINE_BYTES;
+        for (x, pixels) in out.chunks_exact_mut(8).enumerate() {
+            let b = vram[(start + x) % vram.len()];
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = if (b >> (7 - i)) & 1 == 1 { set } else { clear };
+            }
+        }
+    }
+}
+
+/// The picture on the display, one RGB332 byte per pixel
+pub struct Screen {
+    pub pixels: Vec<u8>,
+    /// The control and pan registers the picture was drawn with, if
+    /// it has been drawn at all
+    drawn: Option<(u8, u16)>,
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            pixels: vec![BLACK; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            drawn: None,
+        }
+    }
+
+    /// Bring the picture up to date, returning the rows redrawn, or
+    /// None if nothing has changed.
+    pub fn update(&mut self, video: &Video, vram: &mut VideoRam) -> Option<Range<usize>> {
+        let registers = (video.control, video.pan);
+        let all = self.drawn != Some(registers);
+        if !all && !vram.changed() {
+            return None;
+        }
+
+        let mut redrawn: Option<Range<usize>> = None;
+        let rows = self.pixels.chunks_exact_mut(DISPLAY_WIDTH);
+        for (row, out) in rows.enumerate() {
+            let (first, last) = video.lines(row);
+            if all || vram.line_changed(first) || vram.line_changed(last) {
+                video.render_row(vram.mem(), row, out);
+                let start = redrawn.map_or(row, |r| r.start);
+                redrawn = Some(start..row + 1);
+            }
+        }
+
+        vram.clear_changes();
+        self.drawn = Some(registers);
+        redrawn
     }
 }
 
 impl IoDevice for Video {
+    fn service(&mut self, _bus: &mut Bus, _tag: u8) {
+        self.retrace = true;
+        self.update_irq();
+        schedule!(ServiceKey::Video, FRAME);
+    }
+
     fn read_8(&mut self, _bus: &mut Bus, address: usize) -> Result<u8, BusError> {
         debug!("Read 8 (address={:08x})", address);
-        Ok(0)
+        if address < PAN_REGISTER {
+            Ok(self.read_control())
+        } else if address & 1 == 0 {
+            Ok((self.pan >> 8) as u8)
+        } else {
+            Ok(self.pan as u8)
+        }
     }
 
     fn read_16(&mut self, _bus: &mut Bus, address: usize) -> Result<u16, BusError> {
         debug!("Read 16 (address={:08x})", address);
-        Ok(0)
+        if address < PAN_REGISTER {
+            Ok(self.read_control() as u16)
+        } else {
+            Ok(self.pan)
+        }
     }
 
-    fn read_32(&mut self, _bus: &mut Bus, address: usize) -> Result<u32, BusError> {
+    fn read_32(&mut self, bus: &mut Bus, address: usize) -> Result<u32, BusError> {
         debug!("Read 32 (address={:08x})", address);
-        Ok(0)
+        let hi = self.read_16(bus, address)? as u32;
+        let lo = self.read_16(bus, address + 2)? as u32;
+        Ok((hi << 16) | lo)
     }
 
     fn write_8(&mut self, _bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
         debug!("Write 8 (address={:08x} value={:02x})", address, value);
+        if address < PAN_REGISTER {
+            self.write_control(value);
+        } else if address & 1 == 0 {
+            self.pan = (self.pan & 0x00ff) | ((value as u16) << 8);
+        } else {
+            self.pan = (self.pan & 0xff00) | value as u16;
+        }
         Ok(())
     }
 
     fn write_16(&mut self, _bus: &mut Bus, address: usize, value: u16) -> Result<(), BusError> {
         debug!("Write 16 (address={:08x} value={:04x})", address, value);
+        if address < PAN_REGISTER {
+            self.write_control(value as u8);
+        } else {
+            self.pan = value;
+        }
         Ok(())
     }
 
-    fn write_32(&mut self, _bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
+    fn write_32(&mut self, bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
         debug!("Write 32 (address={:08x} value={:08x})", address, value);
-        Ok(())
+        self.write_16(bus, address, (value >> 16) as u16)?;
+        self.write_16(bus, address + 2, value as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut bus = Bus::new();
+        let mut video = Video::new();
+
+        video.write_16(&mut bus, 0x785ffe, 0x1234).unwrap();
+        assert_eq!(0x1234, video.pan);
+        video.write_8(&mut bus, 0x784001, 0x56).unwrap();
+        assert_eq!(0x1256, video.read_16(&mut bus, 0x784000).unwrap());
+
+        video
+            .write_8(&mut bus, 0x782000, CTRL_ENABLE | CTRL_INVERSE)
+            .unwrap();
+        assert_eq!(0x30, video.read_8(&mut bus, 0x783fff).unwrap());
+    }
+
+    #[test]
+    fn test_retrace() {
+        let mut bus = Bus::new();
+        let mut video = Video::new();
+        let control = CTRL_ENABLE | CTRL_RETRACE_ENABLE;
+        video.write_8(&mut bus, 0x782000, control).unwrap();
+
+        video.service(&mut bus, 0);
+        assert_eq!(0xe0, video.read_8(&mut bus, 0x782000).unwrap());
+
+        // Writing the pending bit back acknowledges the retrace
+        // without touching the rest of the register.
+        video
+            .write_8(&mut bus, 0x782000, CTRL_RETRACE | control)
+            .unwrap();
+        assert_eq!(0x60, video.read_8(&mut bus, 0x782000).unwrap());
+    }
+
+    #[test]
+    fn test_render_pan() {
+        let mut bus = Bus::new();
+        let mut video = Video::new();
+        let mut vram = VideoRam::new();
+        let mut screen = Screen::new();
+        let out = |screen: &Screen, x: usize, y: usize| screen.pixels[y * DISPLAY_WIDTH + x];
+
+        // A pixel set at (16, 1)
+        vram.write_8(&mut bus, VRAM_START + LINE_BYTES + 2, 0x80)
+            .unwrap();
+        assert_eq!(Some(0..DISPLAY_HEIGHT), screen.update(&video, &mut vram));
+        assert_eq!(BLACK, out(&screen, 16, 1));
+        assert_eq!(WHITE, out(&screen, 17, 1));
+        assert_eq!(None, screen.update(&video, &mut vram));
+
+        // Panning one line down and one word across brings it to the
+        // top left corner.
+        video.pan = 64 + 1;
+        screen.update(&video, &mut vram);
+        assert_eq!(BLACK, out(&screen, 0, 0));
+
+        // Only the rows showing a written line are redrawn. Panned
+        // half way across, row 2 runs on into line 4.
+        video.pan = 64 + 32;
+        screen.update(&video, &mut vram);
+        vram.write_8(&mut bus, VRAM_START + 4 * LINE_BYTES, 0x80)
+            .unwrap();
+        assert_eq!(Some(2..4), screen.update(&video, &mut vram));
+
+        // Panned to the last line, the second line of the display is
+        // the first of video RAM.
+        vram.write_8(&mut bus, VRAM_START, 0x80).unwrap();
+        video.pan = 1023 * 64;
+        screen.update(&video, &mut vram);
+        assert_eq!(BLACK, out(&screen, 0, 1));
+
+        video.control = CTRL_ENABLE | CTRL_INVERSE;
+        screen.update(&video, &mut vram);
+        assert_eq!(WHITE, out(&screen, 0, 1));
+        assert_eq!(BLACK, out(&screen, 1, 1));
+
+        video.control = 0;
+        screen.update(&video, &mut vram);
+        assert!(screen.pixels.iter().all(|p| *p == BLACK));
     }
 }
@@ -0,0 +1,149 @@
+//! Video RAM
+//
+// Copyright 2020 Seth Morabito <web@loomcom.com>
+//
+// Permission is hereby granted, free of charge, to any person
+// obtaining a copy of this software and associated documentation
+// files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy,
+// modify, merge, publish, distribute, sublicense, and/or sell copies
+// of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT
+// HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+// WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+//
+use crate::bus::*;
+use crate::err::*;
+use crate::mem::Memory;
+
+use std::result::Result;
+
+// Video RAM holds the 1024x1024 framebuffer, one bit per pixel, most
+// significant bit leftmost. It behaves like any other RAM, but keeps
+// note of which lines of the framebuffer have been written since the
+// display last looked, so that only those need drawing again.
+
+/// Bytes in a line of the framebuffer
+pub const LINE_BYTES: usize = 1024 / 8;
+/// Lines in the framebuffer
+pub const LINES: usize = VRAM_SIZE / LINE_BYTES;
+
+pub struct VideoRam {
+    ram: Memory,
+    /// Lines written since changes were last cleared
+    dirty: Vec<bool>,
+    changed: bool,
+}
+
+impl VideoRam {
+    pub fn new() -> Self {
+        VideoRam {
+            ram: Memory::new(VRAM_START, VRAM_END, VRAM_SIZE, false).unwrap(),
+            dirty: vec![false; LINES],
+            changed: false,
+        }
+    }
+
+    pub fn mem(&self) -> &[u8] {
+        &self.ram.mem
+    }
+
+    /// True if anything has been written since changes were last
+    /// cleared.
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// True if framebuffer line `line` has been written since changes
+    /// were last cleared.
+    pub fn line_changed(&self, line: usize) -> bool {
+        self.dirty[line]
+    }
+
+    pub fn clear_changes(&mut self) {
+        if self.changed {
+            self.dirty.fill(false);
+            self.changed = false;
+        }
+    }
+
+    /// Note a write of `len` bytes at `address`.
+    fn mark(&mut self, address: usize, len: usize) {
+        let offset = (address - VRAM_START) % VRAM_SIZE;
+        self.dirty[offset / LINE_BYTES] = true;
+        self.dirty[(offset + len - 1) % VRAM_SIZE / LINE_BYTES] = true;
+        self.changed = true;
+    }
+}
+
+impl IoDevice for VideoRam {
+    fn read_8(&mut self, bus: &mut Bus, address: usize) -> Result<u8, BusError> {
+        self.ram.read_8(bus, address)
+    }
+
+    fn read_16(&mut self, bus: &mut Bus, address: usize) -> Result<u16, BusError> {
+        self.ram.read_16(bus, address)
+    }
+
+    fn read_32(&mut self, bus: &mut Bus, address: usize) -> Result<u32, BusError> {
+        self.ram.read_32(bus, address)
+    }
+
+    fn write_8(&mut self, bus: &mut Bus, address: usize, value: u8) -> Result<(), BusError> {
+        self.ram.write_8(bus, address, value)?;
+        self.mark(address, 1);
+        Ok(())
+    }
+
+    fn write_16(&mut self, bus: &mut Bus, address: usize, value: u16) -> Result<(), BusError> {
+        self.ram.write_16(bus, address, value)?;
+        self.mark(address, 2);
+        Ok(())
+    }
+
+    fn write_32(&mut self, bus: &mut Bus, address: usize, value: u32) -> Result<(), BusError> {
+        self.ram.write_32(bus, address, value)?;
+        self.mark(address, 4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_lines() {
+        let mut bus = Bus::new();
+        let mut vram = VideoRam::new();
+        assert!(!vram.changed());
+
+        vram.write_8(&mut bus, VRAM_START + 3 * LINE_BYTES, 0xff)
+            .unwrap();
+        assert!(vram.changed());
+        assert!(vram.line_changed(3));
+        assert!(!vram.line_changed(4));
+
+        // A long word written across the end of a line touches both.
+        vram.write_32(&mut bus, VRAM_START + 5 * LINE_BYTES - 2, 0)
+            .unwrap();
+        assert!(vram.line_changed(4));
+        assert!(vram.line_changed(5));
+
+        // Reads leave it alone.
+        vram.clear_changes();
+        vram.read_8(&mut bus, VRAM_START).unwrap();
+        assert!(!vram.changed());
+        assert!(!vram.line_changed(3));
+    }
+}